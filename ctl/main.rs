@@ -0,0 +1,71 @@
+// shared with the degrader
+#[path = "../src/control_socket.rs"]
+mod control_socket;
+
+use clap::{App, AppSettings, Arg};
+use control_socket::DEFAULT_CONTROL_SOCKET;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+
+const COMMANDS: &str = "commands:
+    list                                 list the connections as <queue>:<id>
+    show [<connections>]                 show the models of the connections
+    set <connections> [<model>...]       replace the models, none to forward undegraded
+    models                               show the configured models of both directions
+    counters                             show the packet counters of the queues
+    phases                               list the phases given with --phase or the scenario
+    phase <name>                         switch all connections to the models of a phase
+    pause                                let new packets pass without degradation
+    resume                               degrade new packets again
+    flush [<connections>]                accept all queued packets immediately
+
+connections: all (default), uplink, downlink or <queue>:<id>
+models: random:<loss>,<delay_min>,<delay_max> bandwidth:<rate>,<burst>,<buffer>
+        gilbert_elliott:<p>,<r>,<bad_loss>,<good_loss>";
+
+fn main() {
+    let matches = App::new("degraderctl")
+        .version("1.0.0")
+        .author("Holger Kaden <holger.kaden@logmein.com>")
+        .about("inspect and change the degradation of a running nfqueue degrader")
+        .after_help(COMMANDS)
+        .setting(AppSettings::TrailingVarArg)
+        .arg(
+            Arg::with_name("socket")
+                .long("socket")
+                .takes_value(true)
+                .default_value(DEFAULT_CONTROL_SOCKET)
+                .help("control socket of the degrader"),
+        )
+        .arg(
+            Arg::with_name("command")
+                .required(true)
+                .multiple(true)
+                .help("command and its arguments"),
+        )
+        .get_matches();
+
+    let socket = matches.value_of("socket").unwrap();
+    let command: Vec<&str> = matches.values_of("command").unwrap().collect();
+
+    let mut stream = match UnixStream::connect(socket) {
+        Ok(stream) => stream,
+        Err(e) => {
+            eprintln!("can't connect to {}: {}", socket, e);
+            std::process::exit(1);
+        }
+    };
+    let mut response = String::new();
+    let result = stream
+        .write_all(format!("{}\n", command.join(" ")).as_bytes())
+        .and_then(|_| stream.read_to_string(&mut response));
+    if let Err(e) = result {
+        eprintln!("control socket error: {}", e);
+        std::process::exit(1);
+    }
+
+    print!("{}", response);
+    if response.lines().any(|line| line.starts_with("error:")) {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,217 @@
+use crate::nfqueue_wrapper::{NfqPacket, Verdict};
+use crate::queuing_model::{Action, Decision};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::{mpsc, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// pcapng block types
+const SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const INTERFACE_DESCRIPTION: u32 = 1;
+const ENHANCED_PACKET: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+// the packets of NFQUEUE start with the ip header
+const LINKTYPE_RAW: u16 = 101;
+const OPT_END: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+
+enum Message {
+    /// enhanced packet block of a packet id
+    Packet(u32, Vec<u8>),
+    /// flush and stop, acknowledged on the sender
+    Finish(mpsc::Sender<()>),
+}
+
+// blocks are written on the capture thread, so packets are never held up by file i/o
+static CAPTURE: OnceLock<mpsc::Sender<Message>> = OnceLock::new();
+
+/// Starts writing every packet with a verdict to a pcapng file, with the degrader's
+/// decisions as packet comment
+pub fn start(path: &str) -> Result<(), String> {
+    let file = File::create(path).map_err(|e| format!("failed to create {}: {}", path, e))?;
+    let mut writer = BufWriter::new(file);
+    let mut header = Vec::new();
+    header.extend(BYTE_ORDER_MAGIC.to_le_bytes());
+    header.extend(1u16.to_le_bytes());
+    header.extend(0u16.to_le_bytes());
+    // section length unknown
+    header.extend((-1i64).to_le_bytes());
+    write_block(&mut writer, SECTION_HEADER, &header)
+        .and_then(|_| {
+            let mut interface = Vec::new();
+            interface.extend(LINKTYPE_RAW.to_le_bytes());
+            interface.extend(0u16.to_le_bytes());
+            // no snap length, timestamps in microseconds
+            interface.extend(0u32.to_le_bytes());
+            write_block(&mut writer, INTERFACE_DESCRIPTION, &interface)
+        })
+        .map_err(|e| format!("failed to write {}: {}", path, e))?;
+    let (sender, messages) = mpsc::channel();
+    if CAPTURE.set(sender).is_err() {
+        return Err("capture already started".to_string());
+    }
+    std::thread::Builder::new()
+        .name("capture".to_string())
+        .spawn(move || write(writer, messages))
+        .map_err(|e| format!("failed to spawn capture thread: {}", e))?;
+    log::info!("capturing packets to {}", path);
+    Ok(())
+}
+
+/// Writes the packet if a capture was started
+pub fn record(packet: &NfqPacket, verdict: Verdict) {
+    let capture = match CAPTURE.get() {
+        Some(capture) => capture,
+        None => return,
+    };
+    let release = SystemTime::now();
+    let delay = packet.arrival.elapsed();
+    let arrival = release.checked_sub(delay).unwrap_or(release);
+    let micros = since_epoch(arrival).as_micros() as u64;
+
+    let comment = comment(&packet.decisions, verdict, delay, arrival, release);
+    let mut block = Vec::with_capacity(packet.payload.len() + comment.len() + 32);
+    block.extend(0u32.to_le_bytes());
+    block.extend(((micros >> 32) as u32).to_le_bytes());
+    block.extend((micros as u32).to_le_bytes());
+    block.extend((packet.payload.len() as u32).to_le_bytes());
+    block.extend((packet.wire_len.max(packet.payload.len()) as u32).to_le_bytes());
+    block.extend(&packet.payload);
+    pad(&mut block);
+    block.extend(OPT_COMMENT.to_le_bytes());
+    block.extend((comment.len() as u16).to_le_bytes());
+    block.extend(comment.as_bytes());
+    pad(&mut block);
+    block.extend(OPT_END.to_le_bytes());
+    block.extend(0u16.to_le_bytes());
+
+    // the capture thread has stopped after finish
+    let _ = capture.send(Message::Packet(packet.id, block));
+}
+
+/// Writes the buffered packets to the capture file
+pub fn finish() {
+    if let Some(capture) = CAPTURE.get() {
+        let (done, finished) = mpsc::channel();
+        if capture.send(Message::Finish(done)).is_ok() {
+            let _ = finished.recv();
+        }
+    }
+}
+
+fn write(mut writer: BufWriter<File>, messages: mpsc::Receiver<Message>) {
+    for message in messages {
+        match message {
+            Message::Packet(id, block) => {
+                if let Err(e) = write_block(&mut writer, ENHANCED_PACKET, &block) {
+                    log::error!("failed to capture packet {}: {}", id, e);
+                }
+            }
+            Message::Finish(done) => {
+                if let Err(e) = writer.flush() {
+                    log::error!("failed to write capture: {}", e);
+                }
+                let _ = done.send(());
+                return;
+            }
+        }
+    }
+}
+
+// e.g. "verdict: drop, model: bandwidth, delay: 0.120 ms, arrival: ..., release: ...,
+// decisions: random delay 100.000 ms -> bandwidth drop"
+fn comment(
+    decisions: &[Decision],
+    verdict: Verdict,
+    delay: Duration,
+    arrival: SystemTime,
+    release: SystemTime,
+) -> String {
+    format!(
+        "verdict: {}, model: {}, delay: {:.3} ms, arrival: {:.6}, release: {:.6}, decisions: {}",
+        match verdict {
+            Verdict::Accept => "accept",
+            Verdict::Drop => "drop",
+        },
+        responsible_model(decisions, verdict),
+        delay.as_secs_f64() * 1000.0,
+        since_epoch(arrival).as_secs_f64(),
+        since_epoch(release).as_secs_f64(),
+        if decisions.is_empty() {
+            "none".to_string()
+        } else {
+            decisions
+                .iter()
+                .map(|decision| decision.to_string())
+                .collect::<Vec<_>>()
+                .join(" -> ")
+        }
+    )
+}
+
+// the model which dropped the packet, otherwise the one which delayed it longest
+fn responsible_model(decisions: &[Decision], verdict: Verdict) -> &'static str {
+    let responsible = match verdict {
+        Verdict::Drop => decisions
+            .iter()
+            .find(|decision| decision.action == Action::Drop),
+        Verdict::Accept => decisions
+            .iter()
+            .filter_map(|decision| match decision.action {
+                Action::Delay(delay) if delay > Duration::ZERO => Some((delay, decision)),
+                _ => None,
+            })
+            .max_by_key(|(delay, _)| *delay)
+            .map(|(_, decision)| decision),
+    };
+    responsible.map_or("none", |decision| decision.model)
+}
+
+fn since_epoch(time: SystemTime) -> Duration {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default()
+}
+
+fn pad(data: &mut Vec<u8>) {
+    data.resize(data.len().next_multiple_of(4), 0);
+}
+
+fn write_block<W: Write>(writer: &mut W, block_type: u32, body: &[u8]) -> std::io::Result<()> {
+    let total_len = (body.len() + 12) as u32;
+    writer.write_all(&block_type.to_le_bytes())?;
+    writer.write_all(&total_len.to_le_bytes())?;
+    writer.write_all(body)?;
+    writer.write_all(&total_len.to_le_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blame_dropping_or_longest_delaying_model() {
+        let decisions = [
+            Decision::new("random", Action::Delay(Duration::from_millis(20))),
+            Decision::new("bandwidth", Action::Delay(Duration::from_millis(5))),
+        ];
+        assert_eq!(responsible_model(&decisions, Verdict::Accept), "random");
+        let decisions = [
+            Decision::new("random", Action::Delay(Duration::from_millis(20))),
+            Decision::new("bandwidth", Action::Drop),
+        ];
+        assert_eq!(responsible_model(&decisions, Verdict::Drop), "bandwidth");
+        assert_eq!(responsible_model(&[], Verdict::Drop), "none");
+
+        let comment = comment(
+            &decisions,
+            Verdict::Drop,
+            Duration::from_millis(20),
+            UNIX_EPOCH,
+            UNIX_EPOCH + Duration::from_millis(20),
+        );
+        assert_eq!(
+            comment,
+            "verdict: drop, model: bandwidth, delay: 20.000 ms, arrival: 0.000000, \
+             release: 0.020000, decisions: random delay 20.000 ms -> bandwidth drop"
+        );
+    }
+}
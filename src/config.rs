@@ -1,26 +1,106 @@
+use crate::control_socket::DEFAULT_CONTROL_SOCKET;
+use crate::firewall::{FirewallBackend, FirewallRule, RuleMatch};
+use crate::netem;
+use crate::nfqueue_wrapper::{QueueOptions, Verdict};
+use crate::packet_budget::OverflowPolicy;
+use crate::profile;
+use crate::protocol::Direction;
 use crate::queuing_model::pattern_file_queuing_model::{PacketInfo, PatternFileQueuingModel};
-use clap::{App, Arg};
+use crate::scenario::Scenario;
+use crate::shutdown::ShutdownMode;
+use crate::trace::TraceFormat;
+use clap::{App, Arg, ArgMatches};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
 use std::time::Duration;
 
+#[derive(Clone)]
 pub struct RandomQueuingModelConfig {
     pub loss_rate: u32,
     pub delay_range: (Duration, Duration),
 }
 
+#[derive(Clone)]
 pub struct PatternQueuingModelConfig {
+    pub file: String,
     pub packet_info: Vec<PacketInfo>,
 }
 
+#[derive(Clone)]
 pub struct BandwidthQueuingModelConfig {
     pub rate: u64,
     pub burst_size: u64,
     pub buffer_size: u64,
 }
 
+/// Probabilities in %
+#[derive(Clone)]
+pub struct GilbertElliottQueuingModelConfig {
+    /// from the good to the bad state
+    pub p: f64,
+    /// from the bad to the good state
+    pub r: f64,
+    pub bad_loss: f64,
+    pub good_loss: f64,
+}
+
+#[derive(Clone)]
 pub enum QueuingModelConfig {
     PatternFile(PatternQueuingModelConfig),
     Random(RandomQueuingModelConfig),
     Bandwidth(BandwidthQueuingModelConfig),
+    GilbertElliott(GilbertElliottQueuingModelConfig),
+}
+
+// same syntax as parsed by parse_model_spec
+impl std::fmt::Display for QueuingModelConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QueuingModelConfig::PatternFile(cfg) => write!(f, "pattern_file:{}", cfg.file),
+            QueuingModelConfig::Random(cfg) => write!(
+                f,
+                "random:{},{},{}",
+                cfg.loss_rate,
+                cfg.delay_range.0.as_millis(),
+                cfg.delay_range.1.as_millis()
+            ),
+            QueuingModelConfig::Bandwidth(cfg) => write!(
+                f,
+                "bandwidth:{},{},{}",
+                cfg.rate, cfg.burst_size, cfg.buffer_size
+            ),
+            QueuingModelConfig::GilbertElliott(cfg) => write!(
+                f,
+                "gilbert_elliott:{},{},{},{}",
+                cfg.p, cfg.r, cfg.bad_loss, cfg.good_loss
+            ),
+        }
+    }
+}
+
+/// Named set of models that can be switched to at runtime
+#[derive(Clone)]
+pub struct Phase {
+    pub name: String,
+    pub uplink_models: Vec<QueuingModelConfig>,
+    pub downlink_models: Vec<QueuingModelConfig>,
+}
+
+impl Phase {
+    /// Parses `<name> <model>...`, the models apply to both directions
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let mut tokens = value.split_whitespace();
+        let name = tokens.next().ok_or("missing phase name")?;
+        let models = tokens
+            .map(parse_model_spec)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Phase {
+            name: name.to_string(),
+            uplink_models: models.clone(),
+            downlink_models: models,
+        })
+    }
 }
 
 pub enum LogLevel {
@@ -28,159 +108,966 @@ pub enum LogLevel {
     Warning,
     Debug,
 }
+
+impl LogLevel {
+    pub fn to_level_filter(&self) -> log::LevelFilter {
+        match self {
+            LogLevel::Info => log::LevelFilter::Info,
+            LogLevel::Warning => log::LevelFilter::Warn,
+            LogLevel::Debug => log::LevelFilter::Debug,
+        }
+    }
+}
 pub struct Config {
-    pub models: Vec<QueuingModelConfig>,
-    pub queue_num: u16,
+    pub uplink_models: Vec<QueuingModelConfig>,
+    pub downlink_models: Vec<QueuingModelConfig>,
+    pub uplink_dev: Option<u32>,
+    pub queue_range: (u16, u16),
+    pub queue_options: QueueOptions,
+    pub mtu: usize,
     pub log_level: LogLevel,
     pub apply_per_connection: bool,
+    pub target_user: Option<u32>,
+    pub target_process: Option<String>,
+    pub max_queued_bytes: usize,
+    pub max_queued_packets: usize,
+    pub overflow_policy: OverflowPolicy,
+    pub shutdown_mode: ShutdownMode,
+    pub firewall_rule: Option<FirewallRule>,
+    pub control_socket: Option<String>,
+    pub metrics: Option<String>,
+    #[cfg(feature = "http_api")]
+    pub http_api: Option<String>,
+    pub phases: Vec<Phase>,
+    /// interval of the per-connection statistics log, none if disabled
+    pub stats_interval: Option<Duration>,
+    /// pcapng file to write all packets with the degrader's decisions to
+    pub capture: Option<String>,
+    /// file to write one record per packet to
+    pub trace: Option<(String, TraceFormat)>,
+}
+
+// cli argument names of the model options, per direction
+struct ModelArgNames {
+    random: &'static str,
+    pattern_file: &'static str,
+    bandwidth: &'static str,
+    netem: &'static str,
+    spec: &'static str,
+}
+
+// possible values of the options
+const BOOLS: [&str; 2] = ["true", "false"];
+const LOG_LEVELS: [&str; 3] = ["info", "debug", "warn"];
+const VERDICTS: [&str; 2] = ["accept", "drop"];
+const OVERFLOW_POLICIES: [&str; 3] = ["drop_newest", "drop_oldest", "accept"];
+const SHUTDOWN_MODES: [&str; 2] = ["flush", "drain"];
+const CHAINS: [&str; 5] = ["PREROUTING", "INPUT", "FORWARD", "OUTPUT", "POSTROUTING"];
+const FIREWALLS: [&str; 2] = ["iptables", "nftables"];
+const TRACE_FORMATS: [&str; 2] = ["csv", "json"];
+
+const COMMON_MODEL_ARGS: ModelArgNames = ModelArgNames {
+    random: "random",
+    pattern_file: "pattern_file",
+    bandwidth: "bandwidth",
+    netem: "netem",
+    spec: "model",
+};
+
+const UPLINK_MODEL_ARGS: ModelArgNames = ModelArgNames {
+    random: "uplink_random",
+    pattern_file: "uplink_pattern_file",
+    bandwidth: "uplink_bandwidth",
+    netem: "uplink_netem",
+    spec: "uplink_model",
+};
+
+const DOWNLINK_MODEL_ARGS: ModelArgNames = ModelArgNames {
+    random: "downlink_random",
+    pattern_file: "downlink_pattern_file",
+    bandwidth: "downlink_bandwidth",
+    netem: "downlink_netem",
+    spec: "downlink_model",
+};
+
+fn model_args<'a, 'b>(names: &ModelArgNames, help: &'b [String; 5]) -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name(names.bandwidth)
+            .long(names.bandwidth)
+            .multiple(true)
+            .value_name("rate")
+            .takes_value(true)
+            .value_name("burst")
+            .takes_value(true)
+            .value_name("buffer")
+            .takes_value(true)
+            .help(&help[0]),
+        Arg::with_name(names.pattern_file)
+            .long(names.pattern_file)
+            .takes_value(true)
+            .help(&help[1]),
+        Arg::with_name(names.random)
+            .long(names.random)
+            .multiple(true)
+            .value_name("loss")
+            .takes_value(true)
+            .value_name("delay_min")
+            .takes_value(true)
+            .value_name("delay_max")
+            .takes_value(true)
+            .help(&help[2]),
+        Arg::with_name(names.spec)
+            .long(names.spec)
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("spec")
+            .help(&help[3]),
+        Arg::with_name(names.netem)
+            .long(names.netem)
+            .takes_value(true)
+            .value_name("options")
+            .help(&help[4]),
+    ]
+}
+
+fn model_help(suffix: &str) -> [String; 5] {
+    [
+        format!("restrict bandwidth to <rate> KBps, max. burst size is <burst> KB, max. buffer size is <buffer> KB{}", suffix),
+        format!("csv pattern file with delay and drop/accept info per packet{}", suffix),
+        format!("Random <loss> in % with random delay between <delay_min> ms and <delay_max> ms{}", suffix),
+        format!("model spec <name>:<values> (e.g. bandwidth:1000,100,1000, random:1,10,20 or gilbert_elliott:1,25,30,0 for bursty loss with p, r, loss in bad and good state in %), repeat to chain any number of models, all models of the options are chained in command line order{}", suffix),
+        format!("options of a netem qdisc (e.g. \"delay 100ms 20ms loss 0.3% rate 1mbit limit 1000\"), translated into loss, bandwidth and delay models, unsupported options are an error{}", suffix),
+    ]
 }
 
 impl Config {
-    pub fn from_cli() -> Config {
-        let matches = App::new("nfqueue degrader")
-            .version("1.0.0")
-            .author("Holger Kaden <holger.kaden@logmein.com>")
-            .about("network degrader based on iptables with NFQUEUE")
-            .arg(
-                Arg::with_name("queue_num")
-                    .long("queue_num")
-                    .takes_value(true)
-                    .default_value("0")
-                    .help("nfqueue number"),
-            )
-            .arg(
-                Arg::with_name("log_level")
-                    .long("log_level")
-                    .takes_value(true)
-                    .possible_values(&["info", "debug", "warn"])
-                    .default_value("info")
-                    .help("log level"),
-            )
-            .arg(
-                Arg::with_name("bandwidth")
-                .long("bandwidth")
-                .multiple(true)
-                .value_name("rate").takes_value(true)
-                .value_name("burst").takes_value(true)
-                .value_name("buffer").takes_value(true)   
-                .help("restrict bandwidth to <rate> KBps, max. burst size is <burst> KB, max. buffer size is <buffer> KB")
-            )
-            .arg(
-                Arg::with_name("pattern_file")
-                    .long("pattern_file")
-                    .takes_value(true)
-                    .help("csv pattern file with delay and drop/accept info per packet"),
-            )
-            .arg(
-                Arg::with_name("random")
-                    .long("random")
-                    .multiple(true)
-                    .value_name("loss")
-                    .takes_value(true)
-                    .value_name("delay_min")
-                    .takes_value(true)
-                    .value_name("delay_max")
-                    .takes_value(true)
-                    .help("Random <loss> in % with random delay between <delay_min> ms and <delay_max> ms"),
-            )
-            .arg(
-                Arg::with_name("per_connection")
-                    .long("per_connection")
-                    .takes_value(true)
-                    .possible_values(&["true", "false"])
-                    .default_value("true")
-                    .help("apply configured degradation model per connection (source + destination ip/port/protocol)")
-            )
-            .get_matches();
-
-        let log_level = match matches.value_of("log_level").unwrap() {
-            "info" => LogLevel::Info,
+    pub fn models(&self, direction: Direction) -> &[QueuingModelConfig] {
+        match direction {
+            Direction::Uplink => &self.uplink_models,
+            Direction::Downlink => &self.downlink_models,
+        }
+    }
+
+    pub fn from_cli() -> Result<Config, String> {
+        let help = [
+            model_help(""),
+            model_help(", uplink only"),
+            model_help(", downlink only"),
+        ];
+        let matches = app(&help).get_matches();
+        if matches.value_of("profile") == Some("list") {
+            for profile in profile::PROFILES.iter() {
+                println!("{}", profile);
+            }
+            std::process::exit(0);
+        }
+
+        let scenario_path = matches.value_of("config");
+        let Scenario {
+            settings,
+            models: scenario_models,
+            uplink_models: scenario_uplink_models,
+            downlink_models: scenario_downlink_models,
+            phases: scenario_phases,
+        } = match scenario_path {
+            Some(path) => {
+                Scenario::load(path).map_err(|e| format!("invalid scenario {}: {}", path, e))?
+            }
+            None => Scenario::default(),
+        };
+        let options = Options {
+            matches: &matches,
+            settings: &settings,
+            read: RefCell::new(BTreeSet::new()),
+        };
+
+        let log_level = match options.choice("log_level", &LOG_LEVELS)? {
             "debug" => LogLevel::Debug,
             "warn" => LogLevel::Warning,
-            _ => panic!("unknown log level"),
+            _ => LogLevel::Info,
         };
 
-        let queue_num = matches
-            .value_of("queue_num")
-            .unwrap()
-            .parse::<u16>()
-            .unwrap();
+        let queue_range = parse_queue_range(options.value("queue_num").unwrap())?;
 
-        let apply_per_connection = matches
-            .value_of("per_connection")
-            .unwrap()
-            .parse::<bool>()
-            .unwrap();
+        let queue_options = QueueOptions {
+            max_len: options.parse("queue_maxlen")?,
+            copy_range: match options
+                .value("copy_range")
+                .map(|range| range.parse::<u32>())
+            {
+                None => QueueOptions::default().copy_range,
+                Some(Ok(range)) if range >= 128 => range,
+                _ => return Err("copy range must be a number of bytes, at least 128".to_string()),
+            },
+            default_verdict: match options.choice("default_verdict", &VERDICTS)? {
+                "drop" => Verdict::Drop,
+                _ => Verdict::Accept,
+            },
+            fail_open: options.flag("fail_open")?,
+            no_enobufs: options.flag("no_enobufs")?,
+            gso: options.flag("gso")?,
+            // only needed to match the owner of a packet
+            uid_gid: options.is_present("user"),
+            conntrack: options.flag("conntrack")?,
+        };
+        if queue_options.conntrack && !cfg!(feature = "netlink") {
+            return Err("--conntrack needs the netlink backend (--features netlink)".to_string());
+        }
 
-        let mut model_configs = Vec::<QueuingModelConfig>::new();
+        let mtu = match options.value("mtu").unwrap().parse::<usize>() {
+            Ok(mtu) if mtu >= 576 => mtu,
+            _ => return Err("mtu must be a number of bytes, at least 576".to_string()),
+        };
 
-        if let Some(mut values) = matches.values_of("random") {
-            let loss_rate = values.next().unwrap().parse::<u32>().unwrap();
-            let delay_min = values.next().unwrap().parse::<u32>().unwrap();
-            let delay_max = values.next().unwrap().parse::<u32>().unwrap();
+        let apply_per_connection = options.flag("per_connection")?;
 
-            if delay_min > delay_max {
-                eprintln!("min. delay must be smaller equal max. delay");
-                std::process::exit(1);
+        let uplink_dev = match options.value("uplink_dev") {
+            Some(name) => {
+                let c_name = std::ffi::CString::new(name).unwrap_or_default();
+                match unsafe { libc::if_nametoindex(c_name.as_ptr()) } {
+                    0 => return Err(format!("unknown interface {}", name)),
+                    index => Some(index),
+                }
             }
+            None => None,
+        };
 
-            let delay_range = (
-                Duration::from_millis(delay_min as u64),
-                Duration::from_millis(delay_max as u64),
-            );
+        let max_queued_bytes = options.parse("max_queued_bytes")?;
+        let max_queued_packets = options.parse("max_queued_packets")?;
+        let overflow_policy = match options.choice("overflow_policy", &OVERFLOW_POLICIES)? {
+            "drop_oldest" => OverflowPolicy::DropOldest,
+            "accept" => OverflowPolicy::Accept,
+            _ => OverflowPolicy::DropNewest,
+        };
 
-            model_configs.push(QueuingModelConfig::Random(RandomQueuingModelConfig {
-                loss_rate,
-                delay_range,
-            }));
-        }
+        let shutdown_mode = match options.choice("shutdown", &SHUTDOWN_MODES)? {
+            "drain" => ShutdownMode::Drain,
+            _ => ShutdownMode::Flush,
+        };
 
-        if let Some(pattern_file) = matches.value_of("pattern_file") {
-            log::info!("read csv file: {}", pattern_file);
-            match PatternFileQueuingModel::parse_packet_info(&pattern_file) {
-                Ok(packet_info) => {
-                    let config = PatternQueuingModelConfig { packet_info };
-                    model_configs.push(QueuingModelConfig::PatternFile(config));
-                }
-                Err(e) => {
-                    eprintln!("error parsing {}: {}", pattern_file, e);
-                    std::process::exit(1);
-                }
-            }
-        }
+        let backend = match options.choice("firewall", &FIREWALLS)? {
+            "nftables" => FirewallBackend::Nftables,
+            _ => FirewallBackend::Iptables,
+        };
+        let chain = options.choice("chain", &CHAINS)?;
+        let firewall_rule = match options.values("match") {
+            Some(tokens) => Some(FirewallRule {
+                backend,
+                chain: chain.to_string(),
+                rule_match: RuleMatch::parse(&tokens)
+                    .map_err(|e| format!("invalid --match {}: {}", tokens.join(" "), e))?,
+                queue_range,
+            }),
+            None => None,
+        };
 
-        if let Some(mut values) = matches.values_of("bandwidth") {
-            let rate = values.next().unwrap().parse::<u64>().unwrap();
-            let burst_size = values.next().unwrap().parse::<u64>().unwrap();
-            let buffer_size = values.next().unwrap().parse::<u64>().unwrap();
+        let control_socket = if options.is_present("control_socket") {
+            let path = options.value("control_socket");
+            Some(path.unwrap_or(DEFAULT_CONTROL_SOCKET).to_string())
+        } else {
+            None
+        };
 
-            if rate == 0 {
-                eprintln!("bitrate must be larger 0");
-                std::process::exit(1);
-            }
+        let stats_interval = match options.parse::<u64>("stats_interval")? {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds)),
+        };
+
+        let mut phases = options
+            .values("phase")
+            .into_iter()
+            .flatten()
+            .map(|value| Phase::parse(value).map_err(|e| format!("invalid phase {}: {}", value, e)))
+            .collect::<Result<Vec<_>, _>>()?;
+        phases.extend(scenario_phases);
 
-            if burst_size == 0 {
-                eprintln!("burst size cannot be 0, it should cover at least the size of a packet");
-                std::process::exit(1);
+        let target_user = options.value("user").map(parse_user).transpose()?;
+        let target_process = options.value("process").map(|name| name.to_string());
+
+        let common_models = parse_models(&matches, &COMMON_MODEL_ARGS)?;
+        let uplink_only = parse_models(&matches, &UPLINK_MODEL_ARGS)?;
+        let downlink_only = parse_models(&matches, &DOWNLINK_MODEL_ARGS)?;
+        let profile = options.value("profile");
+        let scenario_has_models = !(scenario_models.is_empty()
+            && scenario_uplink_models.is_empty()
+            && scenario_downlink_models.is_empty());
+        // models of one source only, they can't be combined in a meaningful order
+        let sources = [
+            (
+                !(common_models.is_empty() && uplink_only.is_empty() && downlink_only.is_empty()),
+                "model options",
+            ),
+            (profile.is_some(), "a profile"),
+            (scenario_has_models, "the scenario"),
+        ];
+        let given: Vec<&str> = sources
+            .iter()
+            .filter(|(given, _)| *given)
+            .map(|(_, source)| *source)
+            .collect();
+        if given.len() > 1 {
+            return Err(format!(
+                "models are given by {}, use only one of them",
+                given.join(" and ")
+            ));
+        }
+        let (common_models, uplink_only, downlink_only) = match profile {
+            Some(name) => {
+                let profile = profile::find(name).ok_or_else(|| {
+                    format!(
+                        "unknown profile {}, expected one of {} or list",
+                        name,
+                        profile::names().join(", ")
+                    )
+                })?;
+                let (uplink, downlink) = profile
+                    .models()
+                    .map_err(|e| format!("invalid profile {}: {}", name, e))?;
+                (Vec::new(), uplink, downlink)
             }
+            None if scenario_has_models => (
+                scenario_models,
+                scenario_uplink_models,
+                scenario_downlink_models,
+            ),
+            None => (common_models, uplink_only, downlink_only),
+        };
+        let mut uplink_models = common_models.clone();
+        uplink_models.extend(uplink_only);
+        let mut downlink_models = common_models;
+        downlink_models.extend(downlink_only);
 
-            if burst_size > buffer_size {
-                eprintln!("burst size must be smaller equal buffer size");
-                std::process::exit(1);
+        let metrics = options.value("metrics").map(|addr| addr.to_string());
+        #[cfg(feature = "http_api")]
+        // a port only binds to localhost
+        let http_api = options.value("http_api").map(|addr| {
+            if addr.contains(':') {
+                addr.to_string()
+            } else {
+                format!("127.0.0.1:{}", addr)
             }
+        });
+        let capture = options.value("capture").map(|path| path.to_string());
+        let trace_format = match options.choice("trace_format", &TRACE_FORMATS)? {
+            "json" => TraceFormat::JsonLines,
+            _ => TraceFormat::Csv,
+        };
+        let trace = options
+            .value("trace")
+            .map(|path| (path.to_string(), trace_format));
 
-            model_configs.push(QueuingModelConfig::Bandwidth(BandwidthQueuingModelConfig {
-                rate,
-                burst_size,
-                buffer_size,
-            }))
+        if let (Some(path), Some(name)) = (scenario_path, options.unknown_setting()) {
+            return Err(format!(
+                "invalid scenario {}: unknown setting {}",
+                path, name
+            ));
         }
 
-        Config {
-            models: model_configs,
+        Ok(Config {
+            uplink_models,
+            downlink_models,
+            uplink_dev,
             log_level,
-            queue_num,
+            queue_range,
+            queue_options,
+            mtu,
             apply_per_connection,
+            target_user,
+            target_process,
+            max_queued_bytes,
+            max_queued_packets,
+            overflow_policy,
+            shutdown_mode,
+            firewall_rule,
+            control_socket,
+            metrics,
+            #[cfg(feature = "http_api")]
+            http_api,
+            phases,
+            stats_interval,
+            capture,
+            trace,
+        })
+    }
+}
+
+// value of an option given on the command line, else set in the scenario, else its default
+struct Options<'a> {
+    matches: &'a ArgMatches<'a>,
+    settings: &'a BTreeMap<String, Vec<String>>,
+    // names of the options looked up, settings of the scenario never looked up are unknown
+    read: RefCell<BTreeSet<&'static str>>,
+}
+
+impl<'a> Options<'a> {
+    fn values(&self, name: &'static str) -> Option<Vec<&'a str>> {
+        self.read.borrow_mut().insert(name);
+        match self.settings.get(name) {
+            Some(values) if self.matches.occurrences_of(name) == 0 => {
+                Some(values.iter().map(String::as_str).collect())
+            }
+            _ => self.matches.values_of(name).map(Iterator::collect),
         }
     }
+
+    fn value(&self, name: &'static str) -> Option<&'a str> {
+        self.values(name).and_then(|values| values.first().copied())
+    }
+
+    fn is_present(&self, name: &'static str) -> bool {
+        self.read.borrow_mut().insert(name);
+        self.matches.is_present(name) || self.settings.contains_key(name)
+    }
+
+    // values of the scenario aren't checked by clap
+    fn choice(&self, name: &'static str, choices: &[&str]) -> Result<&'a str, String> {
+        let value = self.value(name).unwrap_or_default();
+        if choices.contains(&value) {
+            Ok(value)
+        } else {
+            Err(format!(
+                "invalid {} {}, expected one of {}",
+                name,
+                value,
+                choices.join(", ")
+            ))
+        }
+    }
+
+    fn flag(&self, name: &'static str) -> Result<bool, String> {
+        Ok(self.choice(name, &BOOLS)? == "true")
+    }
+
+    fn parse<T: std::str::FromStr>(&self, name: &'static str) -> Result<T, String>
+    where
+        T::Err: std::fmt::Display,
+    {
+        let value = self.value(name).unwrap_or_default();
+        value
+            .parse()
+            .map_err(|e| format!("invalid {} {}: {}", name, value, e))
+    }
+
+    fn unknown_setting(&self) -> Option<&'a str> {
+        let read = self.read.borrow();
+        self.settings
+            .keys()
+            .find(|name| !read.contains(name.as_str()))
+            .map(String::as_str)
+    }
+}
+
+fn app<'a, 'b>(help: &'b [[String; 5]; 3]) -> App<'a, 'b> {
+    App::new("nfqueue degrader")
+        .version("1.0.0")
+        .author("Holger Kaden <holger.kaden@logmein.com>")
+        .about("network degrader based on iptables with NFQUEUE")
+        .arg(
+            Arg::with_name("queue_num")
+                .long("queue_num")
+                .takes_value(true)
+                .default_value("0")
+                .help("nfqueue number or range <first>:<last> (iptables --queue-balance), one worker thread per queue"),
+        )
+        .arg(
+            Arg::with_name("queue_maxlen")
+                .long("queue_maxlen")
+                .takes_value(true)
+                .default_value("1073741824")
+                .help("max. number of packets waiting in the kernel queue"),
+        )
+        .arg(
+            Arg::with_name("copy_range")
+                .long("copy_range")
+                .takes_value(true)
+                .help("copy only the first bytes of each packet to the degrader instead of the whole packet, at least 128 (enough for ip and transport headers)"),
+        )
+        .arg(
+            Arg::with_name("default_verdict")
+                .long("default_verdict")
+                .takes_value(true)
+                .possible_values(&VERDICTS)
+                .default_value("accept")
+                .help("verdict for packets the degrader lost track of (e.g. after an internal error), so the kernel doesn't keep them forever"),
+        )
+        .arg(
+            Arg::with_name("fail_open")
+                .long("fail_open")
+                .takes_value(true)
+                .possible_values(&BOOLS)
+                .default_value("false")
+                .help("accept packets instead of dropping them if the kernel queue is full"),
+        )
+        .arg(
+            Arg::with_name("no_enobufs")
+                .long("no_enobufs")
+                .takes_value(true)
+                .possible_values(&BOOLS)
+                .default_value("false")
+                .help("suppress ENOBUFS errors if the degrader can't keep up with the packet rate"),
+        )
+        .arg(
+            Arg::with_name("gso")
+                .long("gso")
+                .takes_value(true)
+                .possible_values(&BOOLS)
+                .default_value("false")
+                .help("let the kernel queue gso packets unsegmented, models count them as mtu sized segments"),
+        )
+        .arg(
+            Arg::with_name("conntrack")
+                .long("conntrack")
+                .takes_value(true)
+                .possible_values(&BOOLS)
+                .default_value("false")
+                .help("let the kernel deliver connection tracking id and state with each packet, netlink backend only"),
+        )
+        .arg(
+            Arg::with_name("mtu")
+                .long("mtu")
+                .takes_value(true)
+                .default_value("1500")
+                .help("mtu in bytes used to split gso packets into segments"),
+        )
+        .arg(
+            Arg::with_name("log_level")
+                .long("log_level")
+                .takes_value(true)
+                .possible_values(&LOG_LEVELS)
+                .default_value("info")
+                .help("log level"),
+        )
+        .args(&model_args(&COMMON_MODEL_ARGS, &help[0]))
+        .args(&model_args(&UPLINK_MODEL_ARGS, &help[1]))
+        .args(&model_args(&DOWNLINK_MODEL_ARGS, &help[2]))
+        .arg(
+            Arg::with_name("per_connection")
+                .long("per_connection")
+                .takes_value(true)
+                .possible_values(&BOOLS)
+                .default_value("true")
+                .help("apply configured degradation model per connection (source + destination ip/port/protocol)")
+        )
+        .arg(
+            Arg::with_name("uplink_dev")
+                .long("uplink_dev")
+                .takes_value(true)
+                .help("interface towards the uplink for routed packets: packets entering through it (PREROUTING) are downlink, packets leaving through it (FORWARD, POSTROUTING) uplink, all others the opposite direction")
+        )
+        .arg(
+            Arg::with_name("max_queued_bytes")
+                .long("max_queued_bytes")
+                .takes_value(true)
+                .default_value("0")
+                .help("max. payload bytes of all packets held by the degrader, 0 is unlimited"),
+        )
+        .arg(
+            Arg::with_name("max_queued_packets")
+                .long("max_queued_packets")
+                .takes_value(true)
+                .default_value("0")
+                .help("max. number of packets held by the degrader, 0 is unlimited"),
+        )
+        .arg(
+            Arg::with_name("overflow_policy")
+                .long("overflow_policy")
+                .takes_value(true)
+                .possible_values(&OVERFLOW_POLICIES)
+                .default_value("drop_newest")
+                .help("what to do with a packet if max_queued_bytes or max_queued_packets is reached: drop it, drop the packets of its queue which arrived first or accept it without degradation"),
+        )
+        .arg(
+            Arg::with_name("shutdown")
+                .long("shutdown")
+                .takes_value(true)
+                .possible_values(&SHUTDOWN_MODES)
+                .default_value("flush")
+                .help("on SIGINT/SIGTERM accept all queued packets immediately (flush) or release them at their scheduled time (drain)"),
+        )
+        .arg(
+            Arg::with_name("match")
+                .long("match")
+                .takes_value(true)
+                .min_values(1)
+                .help("install an NFQUEUE rule for the degrader's queues while it runs, e.g. --match udp dport 40000:40010"),
+        )
+        .arg(
+            Arg::with_name("chain")
+                .long("chain")
+                .takes_value(true)
+                .possible_values(&CHAINS)
+                .default_value("OUTPUT")
+                .help("chain of the rule installed with --match"),
+        )
+        .arg(
+            Arg::with_name("firewall")
+                .long("firewall")
+                .takes_value(true)
+                .possible_values(&FIREWALLS)
+                .default_value("iptables")
+                .help("tool used to install the rule of --match"),
+        )
+        .arg(
+            Arg::with_name("control_socket")
+                .long("control_socket")
+                .takes_value(true)
+                .min_values(0)
+                .max_values(1)
+                .value_name("path")
+                .help("unix socket to inspect and change the degradation at runtime with degraderctl, the default path of degraderctl if given without one"),
+        )
+        .arg(
+            Arg::with_name("metrics")
+                .long("metrics")
+                .takes_value(true)
+                .help("address to serve Prometheus metrics on (http://<address>/metrics), e.g. 127.0.0.1:9100"),
+        )
+        .arg(
+            Arg::with_name("stats_interval")
+                .long("stats_interval")
+                .takes_value(true)
+                .default_value("0")
+                .help("seconds between the logged statistics of each connection and its models, 0 (default) to disable"),
+        )
+        .arg(
+            Arg::with_name("capture")
+                .long("capture")
+                .takes_value(true)
+                .help("write all packets to a pcapng file, with arrival, release, verdict, responsible model and delay as packet comment"),
+        )
+        .arg(
+            Arg::with_name("trace")
+                .long("trace")
+                .takes_value(true)
+                .help("write one record per packet to a file: flow, size, arrival, the decision of each model in chain order and release"),
+        )
+        .arg(
+            Arg::with_name("trace_format")
+                .long("trace_format")
+                .takes_value(true)
+                .possible_values(&TRACE_FORMATS)
+                .default_value("csv")
+                .help("format of the --trace file, csv or json lines"),
+        )
+        .arg(
+            Arg::with_name("phase")
+                .long("phase")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("named set of models to switch to at runtime, e.g. --phase \"congested bandwidth:100,10,200 random:5,50,100\""),
+        )
+        .arg(
+            Arg::with_name("user")
+                .long("user")
+                .takes_value(true)
+                .help("only degrade traffic of sockets owned by this user (name or uid), all other traffic is accepted unchanged")
+        )
+        .arg(
+            Arg::with_name("process")
+                .long("process")
+                .takes_value(true)
+                .help("only degrade traffic of sockets owned by a process with this name, all other traffic is accepted unchanged")
+        )
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .takes_value(true)
+                .help("toml or yaml scenario file with settings named like the options, models and phases, options given on the command line take precedence, its models can't be combined with model options or a profile"),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .takes_value(true)
+                .help("built-in network profile with bursty loss, bandwidth and delay for uplink and downlink, e.g. 3g, lte-good, lte-edge, dsl, cable, geo-satellite, leo-satellite, wifi-congested or edge, list to show all; can't be combined with model options"),
+        )
+        .args(&http_api_args())
+}
+
+#[cfg(feature = "http_api")]
+fn http_api_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![Arg::with_name("http_api")
+        .long("http_api")
+        .takes_value(true)
+        .value_name("[address:]port")
+        .help("address of the REST api to inspect and change the degradation, without authentication, only on localhost if just a port is given, e.g. 8080")]
+}
+
+#[cfg(not(feature = "http_api"))]
+fn http_api_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    Vec::new()
+}
+
+fn parse_user(user: &str) -> Result<u32, String> {
+    if let Ok(uid) = user.parse::<u32>() {
+        return Ok(uid);
+    }
+    let c_name = std::ffi::CString::new(user).unwrap_or_default();
+    let passwd = unsafe { libc::getpwnam(c_name.as_ptr()) };
+    if passwd.is_null() {
+        return Err(format!("unknown user {}", user));
+    }
+    Ok(unsafe { (*passwd).pw_uid })
+}
+
+fn parse_queue_range(range: &str) -> Result<(u16, u16), String> {
+    let parse = |value: &str| {
+        value
+            .parse::<u16>()
+            .map_err(|e| format!("invalid queue number {}: {}", value, e))
+    };
+
+    let range = match range.split_once(':') {
+        Some((first, last)) => (parse(first)?, parse(last)?),
+        None => (parse(range)?, parse(range)?),
+    };
+
+    if range.0 > range.1 {
+        return Err("first queue number must be smaller equal last queue number".to_string());
+    }
+    Ok(range)
+}
+
+// models of the options of one direction in command line order
+fn parse_models(
+    matches: &ArgMatches,
+    names: &ModelArgNames,
+) -> Result<Vec<QueuingModelConfig>, String> {
+    // position on the command line with the model
+    let mut model_configs = Vec::<(usize, QueuingModelConfig)>::new();
+
+    // same checks as a model spec with the values of the option
+    for (model, name) in [
+        ("random", names.random),
+        ("pattern_file", names.pattern_file),
+        ("bandwidth", names.bandwidth),
+    ] {
+        if let (Some(index), Some(values)) = (matches.index_of(name), matches.values_of(name)) {
+            let spec = format!("{}:{}", model, values.collect::<Vec<_>>().join(","));
+            let model_config =
+                parse_model_spec(&spec).map_err(|e| format!("invalid --{}: {}", name, e))?;
+            model_configs.push((index, model_config));
+        }
+    }
+
+    if let (Some(index), Some(options)) =
+        (matches.index_of(names.netem), matches.value_of(names.netem))
+    {
+        let models =
+            netem::parse(options).map_err(|e| format!("invalid --{}: {}", names.netem, e))?;
+        model_configs.extend(models.into_iter().map(|model_config| (index, model_config)));
+    }
+
+    if let (Some(indices), Some(specs)) = (
+        matches.indices_of(names.spec),
+        matches.values_of(names.spec),
+    ) {
+        for (index, spec) in indices.zip(specs) {
+            let model_config = parse_model_spec(spec)
+                .map_err(|e| format!("invalid --{} {}: {}", names.spec, spec, e))?;
+            model_configs.push((index, model_config));
+        }
+    }
+
+    // stable, the models of netem keep their order
+    model_configs.sort_by_key(|(index, _)| *index);
+    Ok(model_configs
+        .into_iter()
+        .map(|(_, model_config)| model_config)
+        .collect())
+}
+
+fn random_model(
+    loss_rate: u32,
+    delay_min: u32,
+    delay_max: u32,
+) -> Result<QueuingModelConfig, String> {
+    if delay_min > delay_max {
+        return Err("min. delay must be smaller equal max. delay".to_string());
+    }
+    Ok(QueuingModelConfig::Random(RandomQueuingModelConfig {
+        loss_rate,
+        delay_range: (
+            Duration::from_millis(delay_min as u64),
+            Duration::from_millis(delay_max as u64),
+        ),
+    }))
+}
+
+fn pattern_file_model(pattern_file: &str) -> Result<QueuingModelConfig, String> {
+    log::info!("read csv file: {}", pattern_file);
+    match PatternFileQueuingModel::parse_packet_info(pattern_file) {
+        Ok(packet_info) => Ok(QueuingModelConfig::PatternFile(PatternQueuingModelConfig {
+            file: pattern_file.to_string(),
+            packet_info,
+        })),
+        Err(e) => Err(format!("error parsing {}: {}", pattern_file, e)),
+    }
+}
+
+fn bandwidth_model(
+    rate: u64,
+    burst_size: u64,
+    buffer_size: u64,
+) -> Result<QueuingModelConfig, String> {
+    if rate == 0 {
+        return Err("bitrate must be larger 0".to_string());
+    }
+    if burst_size == 0 {
+        return Err(
+            "burst size cannot be 0, it should cover at least the size of a packet".to_string(),
+        );
+    }
+    if burst_size > buffer_size {
+        return Err("burst size must be smaller equal buffer size".to_string());
+    }
+    Ok(QueuingModelConfig::Bandwidth(BandwidthQueuingModelConfig {
+        rate,
+        burst_size,
+        buffer_size,
+    }))
+}
+
+fn gilbert_elliott_model(
+    p: f64,
+    r: f64,
+    bad_loss: f64,
+    good_loss: f64,
+) -> Result<QueuingModelConfig, String> {
+    if [p, r, bad_loss, good_loss]
+        .iter()
+        .any(|value| !(0.0..=100.0).contains(value))
+    {
+        return Err("probabilities must be from 0 to 100 %".to_string());
+    }
+    if r == 0.0 {
+        return Err("r must be larger 0, the bad state would never end".to_string());
+    }
+    Ok(QueuingModelConfig::GilbertElliott(
+        GilbertElliottQueuingModelConfig {
+            p,
+            r,
+            bad_loss,
+            good_loss,
+        },
+    ))
+}
+
+// comma separated values of a model spec
+fn spec_values<T: std::str::FromStr>(
+    name: &str,
+    values: &str,
+    count: usize,
+) -> Result<Vec<T>, String>
+where
+    T::Err: std::fmt::Display,
+{
+    let numbers = values
+        .split(',')
+        .map(|value| {
+            value
+                .parse::<T>()
+                .map_err(|e| format!("invalid value {} of {}: {}", value, name, e))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if numbers.len() != count {
+        return Err(format!(
+            "{} expects {} values, got {}",
+            name,
+            count,
+            numbers.len()
+        ));
+    }
+    Ok(numbers)
+}
+
+/// Parses a model given as `<name>:<values>` with the values of the cli option of
+/// the same name separated by commas, e.g. `random:10,0,20` or `bandwidth:1000,1000,1000`.
+/// The gilbert elliott model is given as `gilbert_elliott:<p>,<r>,<bad loss>,<good loss>`
+/// in %, e.g. `gilbert_elliott:1,25,30,0`.
+pub fn parse_model_spec(spec: &str) -> Result<QueuingModelConfig, String> {
+    let (name, values) = spec.split_once(':').unwrap_or((spec, ""));
+    let numbers = |count: usize| spec_values::<u64>(name, values, count);
+    let to_u32 = |value: u64| {
+        u32::try_from(value).map_err(|_| format!("value {} of {} is too large", value, name))
+    };
+    match name {
+        "random" => {
+            let values = numbers(3)?;
+            random_model(to_u32(values[0])?, to_u32(values[1])?, to_u32(values[2])?)
+        }
+        "bandwidth" => {
+            let values = numbers(3)?;
+            bandwidth_model(values[0], values[1], values[2])
+        }
+        "pattern_file" if !values.is_empty() => pattern_file_model(values),
+        "pattern_file" => Err("pattern_file expects a file name".to_string()),
+        "gilbert_elliott" => {
+            let values = spec_values::<f64>(name, values, 4)?;
+            gilbert_elliott_model(values[0], values[1], values[2], values[3])
+        }
+        _ => Err(format!(
+            "unknown model {}, expected random, bandwidth, pattern_file or gilbert_elliott",
+            name
+        )),
+    }
+}
+
+/// Parses a model spec received at runtime, pattern files are only accepted on the
+/// command line so clients can't make the degrader read arbitrary files
+pub fn parse_runtime_model_spec(spec: &str) -> Result<QueuingModelConfig, String> {
+    if spec.split(':').next() == Some("pattern_file") {
+        return Err("pattern_file models can only be given on the command line".to_string());
+    }
+    parse_model_spec(spec)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn models_in_command_line_order() {
+        let help = [
+            model_help(""),
+            model_help(", uplink only"),
+            model_help(", downlink only"),
+        ];
+        let matches = app(&help).get_matches_from(vec![
+            "nfqueue_degrader",
+            "--model",
+            "bandwidth:10,1,10",
+            "--random",
+            "1",
+            "0",
+            "0",
+            "--model",
+            "gilbert_elliott:1,25,30,0",
+            "--netem",
+            "delay 10ms loss 2%",
+            "--bandwidth",
+            "20",
+            "2",
+            "20",
+        ]);
+        let specs: Vec<String> = parse_models(&matches, &COMMON_MODEL_ARGS)
+            .unwrap()
+            .iter()
+            .map(|model| model.to_string())
+            .collect();
+        let netem: Vec<String> = netem::parse("delay 10ms loss 2%")
+            .unwrap()
+            .iter()
+            .map(|model| model.to_string())
+            .collect();
+        let mut expected = vec![
+            "bandwidth:10,1,10".to_string(),
+            "random:1,0,0".to_string(),
+            "gilbert_elliott:1,25,30,0".to_string(),
+        ];
+        expected.extend(netem);
+        expected.push("bandwidth:20,2,20".to_string());
+        assert_eq!(specs, expected);
+    }
 }
@@ -0,0 +1,442 @@
+use crate::config::{self, Phase, QueuingModelConfig};
+use crate::metrics::QueueMetrics;
+use crate::nfqueue_degrader::{Event, Summary};
+use crate::protocol::{Direction, ProtocolInfo};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+// max. time to wait for a command of a client or the reply of a scheduler
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+const USAGE: &str = "expected list, show [<connections>], set <connections> [<model>...], \
+                     models, counters, phases, phase <name>, pause, resume or flush [<connections>]";
+
+/// Connections a command applies to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Selector {
+    All,
+    Direction(Direction),
+    /// `<queue>:<id>` as listed
+    Connection(u16, usize),
+}
+
+impl Selector {
+    pub fn parse(token: &str) -> Result<Self, String> {
+        match token {
+            "all" => Ok(Selector::All),
+            "uplink" => Ok(Selector::Direction(Direction::Uplink)),
+            "downlink" => Ok(Selector::Direction(Direction::Downlink)),
+            _ => token
+                .split_once(':')
+                .and_then(|(queue, id)| {
+                    Some(Selector::Connection(queue.parse().ok()?, id.parse().ok()?))
+                })
+                .ok_or_else(|| {
+                    format!(
+                        "invalid connections {}, expected all, uplink, downlink or <queue>:<id>",
+                        token
+                    )
+                }),
+        }
+    }
+
+    pub fn matches(&self, queue_num: u16, id: usize, direction: Direction) -> bool {
+        match *self {
+            Selector::All => true,
+            Selector::Direction(selected) => selected == direction,
+            Selector::Connection(queue, connection) => queue == queue_num && connection == id,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum Command {
+    /// id, direction and address of all connections
+    List,
+    /// models of the connections
+    Show(Selector),
+    /// replaces the models, for all or a direction also the ones of new connections
+    Set(Selector, Vec<QueuingModelConfig>),
+    /// models of new connections
+    Models,
+    /// packet counts
+    Counters,
+    /// counters and gauges of the queues and connections, for the metrics endpoint
+    Metrics,
+    /// names and models of the phases
+    Phases,
+    /// sets the models of a phase for all connections
+    Phase(String),
+    /// new packets pass without degradation
+    Pause,
+    Resume,
+    /// accepts all queued packets of the connections immediately
+    Flush(Selector),
+}
+
+impl Command {
+    /// Parses a command line, e.g. `set uplink bandwidth:1000,1000,1000 random:1,0,0`
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        match tokens.as_slice() {
+            ["list"] => Ok(Command::List),
+            ["show"] => Ok(Command::Show(Selector::All)),
+            ["show", selector] => Ok(Command::Show(Selector::parse(selector)?)),
+            ["set", selector, models @ ..] => {
+                let selector = Selector::parse(selector)?;
+                let models = models
+                    .iter()
+                    .map(|model| config::parse_runtime_model_spec(model))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(Command::Set(selector, models))
+            }
+            ["models"] => Ok(Command::Models),
+            ["counters"] => Ok(Command::Counters),
+            ["phases"] => Ok(Command::Phases),
+            ["phase", name] => Ok(Command::Phase(name.to_string())),
+            ["pause"] => Ok(Command::Pause),
+            ["resume"] => Ok(Command::Resume),
+            ["flush"] => Ok(Command::Flush(Selector::All)),
+            ["flush", selector] => Ok(Command::Flush(Selector::parse(selector)?)),
+            _ => Err(format!("unknown command '{}', {}", line.trim(), USAGE)),
+        }
+    }
+
+    /// Connections the command applies to, all for commands without
+    pub fn selector(&self) -> Selector {
+        match self {
+            Command::Show(selector) | Command::Set(selector, _) | Command::Flush(selector) => {
+                *selector
+            }
+            _ => Selector::All,
+        }
+    }
+}
+
+/// Command for the scheduler of a queue
+pub struct Request {
+    pub command: Command,
+    pub reply: mpsc::Sender<Result<Reply, ControlError>>,
+}
+
+/// Reason a command failed, displayed as its message
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ControlError {
+    /// the phase or connection the command refers to doesn't exist
+    NotFound(String),
+    /// a scheduler has stopped or didn't reply in time
+    Unavailable(String),
+    /// the command can't be executed
+    Failed(String),
+}
+
+impl std::fmt::Display for ControlError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ControlError::NotFound(message)
+            | ControlError::Unavailable(message)
+            | ControlError::Failed(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+/// Connection as reported by the scheduler of its queue
+pub struct ConnectionState {
+    pub id: usize,
+    pub direction: Direction,
+    /// degraded, not excluded by --user/--process
+    pub targeted: bool,
+    pub connection: ProtocolInfo,
+    /// `Display` of its chain
+    pub models: String,
+}
+
+/// Result of a command on the scheduler of a queue
+pub enum Reply {
+    Connections(Vec<ConnectionState>),
+    Models {
+        uplink: Vec<QueuingModelConfig>,
+        downlink: Vec<QueuingModelConfig>,
+    },
+    Counters(Summary),
+    Metrics(QueueMetrics),
+    /// number of connections with replaced models
+    Replaced(usize),
+    Paused(bool),
+    Flushed {
+        packets: usize,
+        connections: usize,
+    },
+}
+
+/// Reply or error of each queue
+pub type QueueReplies = Vec<(u16, Result<Reply, ControlError>)>;
+
+/// Hands commands to the schedulers of all queues, used by the control socket and the
+/// REST api
+pub struct Controller {
+    schedulers: Vec<(u16, mpsc::Sender<Event>)>,
+    phases: Vec<Phase>,
+}
+
+impl Controller {
+    pub fn new(schedulers: Vec<(u16, mpsc::Sender<Event>)>, phases: Vec<Phase>) -> Self {
+        Controller { schedulers, phases }
+    }
+
+    pub fn phases(&self) -> &[Phase] {
+        &self.phases
+    }
+
+    /// Replies of the schedulers the command applies to, with their queue number
+    pub fn execute(&self, command: &Command) -> Result<QueueReplies, ControlError> {
+        if let Command::Phase(name) = command {
+            let phase = self
+                .phases
+                .iter()
+                .find(|phase| phase.name == *name)
+                .ok_or_else(|| ControlError::NotFound(format!("unknown phase {}", name)))?;
+            let mut replies = self.execute(&Command::Set(
+                Selector::Direction(Direction::Uplink),
+                phase.uplink_models.clone(),
+            ))?;
+            replies.extend(self.execute(&Command::Set(
+                Selector::Direction(Direction::Downlink),
+                phase.downlink_models.clone(),
+            ))?);
+            return Ok(replies);
+        }
+
+        // a connection is only known by the scheduler of its queue
+        let selector = command.selector();
+        let schedulers: Vec<_> = self
+            .schedulers
+            .iter()
+            .filter(|(queue_num, _)| match selector {
+                Selector::Connection(queue, _) => queue == *queue_num,
+                _ => true,
+            })
+            .collect();
+        if let (Selector::Connection(queue, id), true) = (selector, schedulers.is_empty()) {
+            return Err(ControlError::NotFound(format!(
+                "unknown connection {}:{}",
+                queue, id
+            )));
+        }
+
+        Ok(schedulers
+            .into_iter()
+            .map(|(queue_num, sender)| {
+                let (reply_tx, reply_rx) = mpsc::channel();
+                let request = Request {
+                    command: command.clone(),
+                    reply: reply_tx,
+                };
+                let reply = match sender.send(Event::Control(request)) {
+                    Ok(()) => reply_rx.recv_timeout(TIMEOUT).unwrap_or_else(|_| {
+                        Err(ControlError::Unavailable(
+                            "no reply from scheduler".to_string(),
+                        ))
+                    }),
+                    Err(_) => Err(ControlError::Unavailable(
+                        "scheduler has stopped".to_string(),
+                    )),
+                };
+                (*queue_num, reply)
+            })
+            .collect())
+    }
+}
+
+/// Listening control socket, the socket file is removed when dropped
+pub struct ControlSocket {
+    path: String,
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Accepts one command line per client connection. The reply has a line per queue
+/// or connection, errors start with `error:`.
+pub fn serve(path: &str, controller: Arc<Controller>) -> Result<ControlSocket, String> {
+    // a socket left behind by a killed degrader is replaced, one still in use is not
+    if UnixStream::connect(path).is_ok() {
+        return Err(format!("{} is used by another degrader", path));
+    }
+    if std::fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        let _ = std::fs::remove_file(path);
+    }
+    let listener =
+        UnixListener::bind(path).map_err(|e| format!("failed to bind {}: {}", path, e))?;
+
+    std::thread::Builder::new()
+        .name("control".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let result = stream.and_then(|stream| handle_client(stream, &controller));
+                if let Err(e) = result {
+                    log::warn!("control socket: {}", e);
+                }
+            }
+        })
+        .map_err(|e| format!("failed to spawn control thread: {}", e))?;
+    log::info!("control socket listening on {}", path);
+    Ok(ControlSocket {
+        path: path.to_string(),
+    })
+}
+
+fn handle_client(stream: UnixStream, controller: &Controller) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    log::info!("control command: {}", line.trim());
+
+    let replies = Command::parse(&line).and_then(|command| {
+        if let Command::Phases = command {
+            return Ok((command, Vec::new()));
+        }
+        let replies = controller.execute(&command).map_err(|e| e.to_string())?;
+        Ok((command, replies))
+    });
+    let response = match replies {
+        Ok((command, replies)) => {
+            let mut response: String = replies
+                .iter()
+                .map(|(queue_num, reply)| match reply {
+                    Ok(reply) => format_reply(&command, *queue_num, reply),
+                    Err(e) => format!("error: queue {}: {}\n", queue_num, e),
+                })
+                .collect();
+            if let Command::Phases = command {
+                response = format_phases(controller.phases());
+            } else if response.is_empty() {
+                response.push_str("no connections\n");
+            }
+            response
+        }
+        Err(e) => format!("error: {}\n", e),
+    };
+    (&stream).write_all(response.as_bytes())
+}
+
+fn format_models(models: &[QueuingModelConfig]) -> String {
+    let models: Vec<_> = models.iter().map(|model| model.to_string()).collect();
+    if models.is_empty() {
+        "none".to_string()
+    } else {
+        models.join(" ")
+    }
+}
+
+fn format_phases(phases: &[Phase]) -> String {
+    if phases.is_empty() {
+        return "no phases\n".to_string();
+    }
+    phases
+        .iter()
+        .map(|phase| {
+            format!(
+                "{}: uplink {}, downlink {}\n",
+                phase.name,
+                format_models(&phase.uplink_models),
+                format_models(&phase.downlink_models)
+            )
+        })
+        .collect()
+}
+
+fn format_reply(command: &Command, queue_num: u16, reply: &Reply) -> String {
+    match reply {
+        Reply::Connections(connections) => connections
+            .iter()
+            .map(|state| {
+                let mut line = format!(
+                    "{}:{} {} {}{}\n",
+                    queue_num,
+                    state.id,
+                    state.direction,
+                    state.connection,
+                    if state.targeted { "" } else { " (untargeted)" }
+                );
+                if let Command::Show(_) = command {
+                    line.push_str(&format!("    {}\n", state.models));
+                }
+                line
+            })
+            .collect(),
+        Reply::Models { uplink, downlink } => format!(
+            "queue {} uplink: {}\nqueue {} downlink: {}\n",
+            queue_num,
+            format_models(uplink),
+            queue_num,
+            format_models(downlink)
+        ),
+        Reply::Counters(summary) | Reply::Metrics(QueueMetrics { summary, .. }) => {
+            format!("queue {}: {}\n", queue_num, summary)
+        }
+        Reply::Replaced(count) => format!(
+            "queue {}: models of {} connections replaced\n",
+            queue_num, count
+        ),
+        Reply::Paused(paused) => format!(
+            "queue {}: degradation {}\n",
+            queue_num,
+            if *paused { "paused" } else { "resumed" }
+        ),
+        Reply::Flushed {
+            packets,
+            connections,
+        } => format!(
+            "queue {}: {} packets of {} connections flushed\n",
+            queue_num, packets, connections
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_commands() {
+        assert!(matches!(Command::parse("list\n"), Ok(Command::List)));
+        assert!(matches!(
+            Command::parse("show 1:12"),
+            Ok(Command::Show(Selector::Connection(1, 12)))
+        ));
+        match Command::parse("set uplink bandwidth:1000,100,1000 random:1,10,20") {
+            Ok(Command::Set(Selector::Direction(Direction::Uplink), models)) => {
+                assert!(matches!(
+                    models.as_slice(),
+                    [
+                        QueuingModelConfig::Bandwidth(_),
+                        QueuingModelConfig::Random(_)
+                    ]
+                ))
+            }
+            _ => panic!("set not parsed"),
+        }
+        assert!(matches!(
+            Command::parse("set all"),
+            Ok(Command::Set(Selector::All, models)) if models.is_empty()
+        ));
+        assert!(matches!(
+            Command::parse("flush"),
+            Ok(Command::Flush(Selector::All))
+        ));
+
+        assert!(Command::parse("set all random:1,20,10").is_err());
+        assert!(Command::parse("set all random:1,20").is_err());
+        assert!(Command::parse("set all pattern_file:/etc/shadow").is_err());
+        assert!(Command::parse("set sideways").is_err());
+        assert!(Command::parse("flush 1:x").is_err());
+        assert!(Command::parse("reboot").is_err());
+    }
+}
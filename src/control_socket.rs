@@ -0,0 +1,2 @@
+/// Default path of the control socket, shared by the degrader and degraderctl
+pub const DEFAULT_CONTROL_SOCKET: &str = "/run/nfqueue-degrader.sock";
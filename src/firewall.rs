@@ -0,0 +1,285 @@
+use std::process::Command;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FirewallBackend {
+    Iptables,
+    Nftables,
+}
+
+/// Packets to queue: protocol (all if none) and port ranges
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RuleMatch {
+    pub protocol: Option<String>,
+    pub sport: Option<(u16, u16)>,
+    pub dport: Option<(u16, u16)>,
+}
+
+impl RuleMatch {
+    /// Parses e.g. `udp dport 40000:40010` or `tcp sport 443`
+    pub fn parse(tokens: &[&str]) -> Result<Self, String> {
+        let (protocol, mut rest) = match tokens.split_first() {
+            Some((protocol, rest)) => (*protocol, rest),
+            None => return Err("missing protocol".to_string()),
+        };
+        let mut rule_match = RuleMatch::default();
+        match protocol {
+            "all" => {}
+            "tcp" | "udp" | "icmp" => rule_match.protocol = Some(protocol.to_string()),
+            _ => {
+                return Err(format!(
+                    "unknown protocol {}, expected tcp, udp, icmp or all",
+                    protocol
+                ))
+            }
+        }
+
+        while let [direction, range, remaining @ ..] = rest {
+            if protocol != "tcp" && protocol != "udp" {
+                return Err(format!("ports can't be matched for {}", protocol));
+            }
+            let range = parse_port_range(range)?;
+            match *direction {
+                "sport" => rule_match.sport = Some(range),
+                "dport" => rule_match.dport = Some(range),
+                _ => {
+                    return Err(format!(
+                        "unknown match {}, expected sport or dport",
+                        direction
+                    ))
+                }
+            }
+            rest = remaining;
+        }
+        if let [token] = rest {
+            return Err(format!("missing port after {}", token));
+        }
+        Ok(rule_match)
+    }
+}
+
+fn parse_port_range(range: &str) -> Result<(u16, u16), String> {
+    let parse = |port: &str| {
+        port.parse::<u16>()
+            .map_err(|e| format!("invalid port {}: {}", port, e))
+    };
+    let (first, last) = match range.split_once(':') {
+        Some((first, last)) => (parse(first)?, parse(last)?),
+        None => (parse(range)?, parse(range)?),
+    };
+    if first > last {
+        return Err(format!("invalid port range {}", range));
+    }
+    Ok((first, last))
+}
+
+/// NFQUEUE rule installed by the degrader for its queues
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FirewallRule {
+    pub backend: FirewallBackend,
+    /// PREROUTING, INPUT, FORWARD, OUTPUT or POSTROUTING
+    pub chain: String,
+    pub rule_match: RuleMatch,
+    pub queue_range: (u16, u16),
+}
+
+// identifies the rules of the degrader in the iptables listing
+const RULE_COMMENT: &str = "nfqueue-degrader";
+
+impl FirewallRule {
+    // the mangle table has all five chains. With --queue-bypass packets pass if the
+    // degrader is gone without removing its rule.
+    fn iptables_args(&self, action: &str) -> Vec<String> {
+        let mut args = strings(&["-t", "mangle", action, &self.chain]);
+        if let Some(protocol) = &self.rule_match.protocol {
+            args.extend(["-p".to_string(), protocol.clone()]);
+        }
+        if let Some(range) = self.rule_match.sport {
+            args.extend(["--sport".to_string(), format_range(range, ':')]);
+        }
+        if let Some(range) = self.rule_match.dport {
+            args.extend(["--dport".to_string(), format_range(range, ':')]);
+        }
+        args.extend(strings(&[
+            "-m",
+            "comment",
+            "--comment",
+            RULE_COMMENT,
+            "-j",
+            "NFQUEUE",
+        ]));
+        let (first, last) = self.queue_range;
+        if first == last {
+            args.extend(["--queue-num".to_string(), first.to_string()]);
+        } else {
+            args.extend(["--queue-balance".to_string(), format!("{}:{}", first, last)]);
+        }
+        args.push("--queue-bypass".to_string());
+        args
+    }
+
+    // own table per degrader instance, removing it removes everything
+    fn nft_table(&self) -> String {
+        format!("nfqueue_degrader_{}", self.queue_range.0)
+    }
+
+    fn nft_setup_commands(&self) -> Vec<Vec<String>> {
+        let table = self.nft_table();
+        let chain = self.chain.to_lowercase();
+
+        let mut rule = strings(&["add", "rule", "ip", &table, &chain]);
+        match &self.rule_match.protocol {
+            Some(protocol)
+                if self.rule_match.sport.is_some() || self.rule_match.dport.is_some() =>
+            {
+                if let Some(range) = self.rule_match.sport {
+                    rule.extend([
+                        protocol.clone(),
+                        "sport".to_string(),
+                        format_range(range, '-'),
+                    ]);
+                }
+                if let Some(range) = self.rule_match.dport {
+                    rule.extend([
+                        protocol.clone(),
+                        "dport".to_string(),
+                        format_range(range, '-'),
+                    ]);
+                }
+            }
+            Some(protocol) => {
+                rule.extend(["meta".to_string(), "l4proto".to_string(), protocol.clone()])
+            }
+            None => {}
+        }
+        rule.extend([
+            "queue".to_string(),
+            "num".to_string(),
+            format_range(self.queue_range, '-'),
+            "bypass".to_string(),
+        ]);
+
+        let add_table = strings(&["add", "table", "ip", &table]);
+        let add_chain = strings(&[
+            "add", "chain", "ip", &table, &chain, "{", "type", "filter", "hook", &chain,
+            "priority", "0", ";", "}",
+        ]);
+        vec![add_table, add_chain, rule]
+    }
+
+    fn nft_cleanup_command(&self) -> Vec<String> {
+        strings(&["delete", "table", "ip", &self.nft_table()])
+    }
+
+    fn remove(&self) -> Result<(), String> {
+        match self.backend {
+            FirewallBackend::Iptables => run("iptables", &self.iptables_args("-D")),
+            FirewallBackend::Nftables => run("nft", &self.nft_cleanup_command()),
+        }
+    }
+}
+
+fn strings(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+fn format_range((first, last): (u16, u16), separator: char) -> String {
+    if first == last {
+        first.to_string()
+    } else {
+        format!("{}{}{}", first, separator, last)
+    }
+}
+
+fn run(program: &str, args: &[String]) -> Result<(), String> {
+    log::info!("{} {}", program, args.join(" "));
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|e| format!("failed to run {}: {}", program, e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "{} {} failed: {}",
+            program,
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+/// Removes the installed rule when dropped, on exit and when unwinding from a panic
+pub struct RuleGuard {
+    rule: FirewallRule,
+}
+
+impl Drop for RuleGuard {
+    fn drop(&mut self) {
+        if let Err(e) = self.rule.remove() {
+            log::error!("failed to remove firewall rule: {}", e);
+            eprintln!("failed to remove firewall rule: {}", e);
+        }
+    }
+}
+
+pub fn install(rule: FirewallRule) -> Result<RuleGuard, String> {
+    match rule.backend {
+        FirewallBackend::Iptables => run("iptables", &rule.iptables_args("-I"))?,
+        FirewallBackend::Nftables => {
+            for command in rule.nft_setup_commands() {
+                if let Err(e) = run("nft", &command) {
+                    // the table may already have been added
+                    let _ = rule.remove();
+                    return Err(e);
+                }
+            }
+        }
+    }
+    Ok(RuleGuard { rule })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_rule_match() {
+        let rule_match = RuleMatch::parse(&["udp", "dport", "40000:40010", "sport", "53"]).unwrap();
+        assert_eq!(rule_match.protocol.as_deref(), Some("udp"));
+        assert_eq!(rule_match.dport, Some((40000, 40010)));
+        assert_eq!(rule_match.sport, Some((53, 53)));
+
+        assert_eq!(RuleMatch::parse(&["all"]).unwrap(), RuleMatch::default());
+        assert!(RuleMatch::parse(&["icmp", "dport", "1"]).is_err());
+        assert!(RuleMatch::parse(&["udp", "dport"]).is_err());
+        assert!(RuleMatch::parse(&["udp", "dport", "20:10"]).is_err());
+        assert!(RuleMatch::parse(&["sctp"]).is_err());
+    }
+
+    #[test]
+    fn firewall_commands() {
+        let rule = FirewallRule {
+            backend: FirewallBackend::Iptables,
+            chain: "OUTPUT".to_string(),
+            rule_match: RuleMatch::parse(&["udp", "dport", "40000:40010"]).unwrap(),
+            queue_range: (0, 3),
+        };
+        assert_eq!(
+            rule.iptables_args("-I").join(" "),
+            "-t mangle -I OUTPUT -p udp --dport 40000:40010 -m comment --comment nfqueue-degrader \
+             -j NFQUEUE --queue-balance 0:3 --queue-bypass"
+        );
+        let commands: Vec<_> = rule
+            .nft_setup_commands()
+            .iter()
+            .map(|command| command.join(" "))
+            .collect();
+        assert_eq!(
+            commands,
+            vec![
+                "add table ip nfqueue_degrader_0",
+                "add chain ip nfqueue_degrader_0 output { type filter hook output priority 0 ; }",
+                "add rule ip nfqueue_degrader_0 output udp dport 40000-40010 queue num 0-3 bypass",
+            ]
+        );
+    }
+}
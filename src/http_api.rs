@@ -0,0 +1,378 @@
+use crate::config::{self, QueuingModelConfig};
+use crate::control::{Command, ControlError, Controller, Reply, Selector};
+use crate::nfqueue_degrader::Summary;
+use crate::nfqueue_wrapper::default_verdict_count;
+use crate::protocol::Direction;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tiny_http::{Header, Method, Response, Server};
+
+// status code and message of a failed request
+type Error = (u16, String);
+
+fn bad_request(message: String) -> Error {
+    (400, message)
+}
+
+fn failed(error: ControlError) -> Error {
+    let status = match error {
+        ControlError::NotFound(_) => 404,
+        ControlError::Unavailable(_) => 503,
+        ControlError::Failed(_) => 500,
+    };
+    (status, error.to_string())
+}
+
+/// Serves the REST api on its own thread:
+///
+/// - `GET /connections`, `PUT /connections/<queue>:<id>/models`
+/// - `GET /models`, `PUT /models` (models of all and new connections per direction)
+/// - `GET /counters`
+/// - `GET /phases`, `POST /phases/<name>`
+/// - `POST /pause`, `POST /resume`, `POST /flush`
+///
+/// Models are given as json arrays of model specs, e.g. `["bandwidth:1000,100,1000"]`,
+/// pattern files are rejected. There is no authentication, anyone who can connect can
+/// change the degradation.
+/// Failures are 400 for invalid requests, 404 for unknown endpoints, phases and
+/// connections, 503 if a scheduler doesn't reply and 500 otherwise.
+pub fn serve(addr: &str, controller: Arc<Controller>) -> Result<(), String> {
+    let server = Server::http(addr).map_err(|e| format!("failed to listen on {}: {}", addr, e))?;
+    std::thread::Builder::new()
+        .name("http_api".to_string())
+        .spawn(move || {
+            for mut request in server.incoming_requests() {
+                let mut body = String::new();
+                let result = match request.as_reader().read_to_string(&mut body) {
+                    Ok(_) => route(&controller, request.method(), request.url(), &body),
+                    Err(e) => Err(bad_request(format!("failed to read body: {}", e))),
+                };
+                let (status, value) = match result {
+                    Ok(value) => (200, value),
+                    Err((status, message)) => (status, json!({ "error": message })),
+                };
+                log::info!("{} {}: {}", request.method(), request.url(), status);
+                let response = Response::from_string(value.to_string())
+                    .with_status_code(status)
+                    .with_header(
+                        Header::from_bytes("Content-Type", "application/json")
+                            .expect("valid header"),
+                    );
+                if let Err(e) = request.respond(response) {
+                    log::warn!("REST api: {}", e);
+                }
+            }
+        })
+        .map_err(|e| format!("failed to spawn REST api thread: {}", e))?;
+    log::info!("REST api listening on {}", addr);
+    Ok(())
+}
+
+fn route(controller: &Controller, method: &Method, url: &str, body: &str) -> Result<Value, Error> {
+    let path = url.split('?').next().unwrap_or_default();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (method, segments.as_slice()) {
+        (Method::Get, ["connections"]) => {
+            let replies = execute(controller, &Command::Show(Selector::All))?;
+            let mut connections = Vec::new();
+            for (queue_num, reply) in replies {
+                if let Reply::Connections(states) = reply {
+                    connections.extend(states.iter().map(|state| {
+                        json!({
+                            "id": format!("{}:{}", queue_num, state.id),
+                            "direction": state.direction.to_string(),
+                            "targeted": state.targeted,
+                            "source": format!("{}:{}", state.connection.source_ip_to_string(), state.connection.source_port),
+                            "destination": format!("{}:{}", state.connection.destination_ip_to_string(), state.connection.destination_port),
+                            "protocol": state.connection.protocol,
+                            "models": state.models,
+                        })
+                    }));
+                }
+            }
+            Ok(Value::Array(connections))
+        }
+        (Method::Put, ["connections", id, "models"]) => {
+            let selector = Selector::parse(id).map_err(bad_request)?;
+            if let Selector::Connection(..) = selector {
+                let models = parse_models(&parse_body(body)?)?;
+                let replies = execute(controller, &Command::Set(selector, models))?;
+                Ok(json!({ "replaced": replaced(replies) }))
+            } else {
+                Err(bad_request(format!("invalid connection id {}", id)))
+            }
+        }
+        (Method::Get, ["models"]) => {
+            let replies = execute(controller, &Command::Models)?;
+            match replies.first() {
+                Some((_, Reply::Models { uplink, downlink })) => Ok(json!({
+                    "uplink": model_specs(uplink),
+                    "downlink": model_specs(downlink),
+                })),
+                _ => Err((500, "no models reported".to_string())),
+            }
+        }
+        (Method::Put, ["models"]) => {
+            let body = parse_body(body)?;
+            // all models are validated before any are replaced
+            let mut commands = Vec::new();
+            for direction in [Direction::Uplink, Direction::Downlink] {
+                if let Some(models) = body.get(direction.to_string()) {
+                    commands.push(Command::Set(
+                        Selector::Direction(direction),
+                        parse_models(models)?,
+                    ));
+                }
+            }
+            if commands.is_empty() {
+                return Err(bad_request(
+                    "expected the models of uplink and/or downlink".to_string(),
+                ));
+            }
+            let mut count = 0;
+            for command in commands {
+                count += replaced(execute(controller, &command)?);
+            }
+            Ok(json!({ "replaced": count }))
+        }
+        (Method::Get, ["counters"]) => {
+            let mut total = Summary::default();
+            let mut queues = Vec::new();
+            for (queue_num, reply) in execute(controller, &Command::Counters)? {
+                if let Reply::Counters(summary) = reply {
+                    total.add(&summary);
+                    let mut counters = counters_json(&summary);
+                    counters["queue"] = json!(queue_num);
+                    queues.push(counters);
+                }
+            }
+            let mut counters = counters_json(&total);
+            counters["without_verdict"] = json!(default_verdict_count());
+            counters["queues"] = Value::Array(queues);
+            Ok(counters)
+        }
+        (Method::Get, ["phases"]) => {
+            let phases: Vec<_> = controller
+                .phases()
+                .iter()
+                .map(|phase| {
+                    json!({
+                        "name": phase.name,
+                        "uplink": model_specs(&phase.uplink_models),
+                        "downlink": model_specs(&phase.downlink_models),
+                    })
+                })
+                .collect();
+            Ok(Value::Array(phases))
+        }
+        (Method::Post, ["phases", name]) => {
+            let replies = execute(controller, &Command::Phase(name.to_string()))?;
+            Ok(json!({ "replaced": replaced(replies) }))
+        }
+        (Method::Post, [action @ ("pause" | "resume")]) => {
+            let command = if *action == "pause" {
+                Command::Pause
+            } else {
+                Command::Resume
+            };
+            execute(controller, &command)?;
+            Ok(json!({ "paused": *action == "pause" }))
+        }
+        (Method::Post, ["flush"]) => {
+            let (mut packets, mut connections) = (0, 0);
+            for (_, reply) in execute(controller, &Command::Flush(Selector::All))? {
+                if let Reply::Flushed {
+                    packets: flushed,
+                    connections: flushed_connections,
+                } = reply
+                {
+                    packets += flushed;
+                    connections += flushed_connections;
+                }
+            }
+            Ok(json!({ "packets": packets, "connections": connections }))
+        }
+        _ => Err((404, format!("unknown endpoint {} {}", method, path))),
+    }
+}
+
+// replies of all queues, the first error fails the request
+fn execute(controller: &Controller, command: &Command) -> Result<Vec<(u16, Reply)>, Error> {
+    controller
+        .execute(command)
+        .map_err(failed)?
+        .into_iter()
+        .map(|(queue_num, reply)| {
+            reply.map(|reply| (queue_num, reply)).map_err(|e| {
+                let (status, message) = failed(e);
+                (status, format!("queue {}: {}", queue_num, message))
+            })
+        })
+        .collect()
+}
+
+// number of connections with replaced models
+fn replaced(replies: Vec<(u16, Reply)>) -> usize {
+    replies
+        .into_iter()
+        .map(|(_, reply)| match reply {
+            Reply::Replaced(count) => count,
+            _ => 0,
+        })
+        .sum()
+}
+
+fn parse_body(body: &str) -> Result<Value, Error> {
+    serde_json::from_str(body).map_err(|e| bad_request(format!("invalid json: {}", e)))
+}
+
+fn parse_models(value: &Value) -> Result<Vec<QueuingModelConfig>, Error> {
+    let specs = value
+        .as_array()
+        .ok_or_else(|| bad_request("models must be an array of model specs".to_string()))?;
+    specs
+        .iter()
+        .map(|spec| match spec.as_str() {
+            Some(spec) => config::parse_runtime_model_spec(spec).map_err(bad_request),
+            None => Err(bad_request(format!("invalid model spec {}", spec))),
+        })
+        .collect()
+}
+
+fn model_specs(models: &[QueuingModelConfig]) -> Vec<String> {
+    models.iter().map(|model| model.to_string()).collect()
+}
+
+fn counters_json(summary: &Summary) -> Value {
+    json!({
+        "received": summary.received,
+        "released": summary.released,
+        "accepted": summary.accepted,
+        "dropped": summary.dropped(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Phase;
+    use crate::nfqueue_degrader::Event;
+    use std::sync::mpsc;
+
+    // controller of one queue whose scheduler replies with models or the number of
+    // replaced connections, and one of a stopped scheduler
+    fn controller() -> Controller {
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            for event in receiver {
+                if let Event::Control(request) = event {
+                    let reply = match request.command {
+                        Command::Models => Ok(Reply::Models {
+                            uplink: Vec::new(),
+                            downlink: vec![config::parse_model_spec("random:1,0,0").unwrap()],
+                        }),
+                        Command::Set(Selector::Connection(_, id), _) if id != 1 => Err(
+                            ControlError::NotFound(format!("unknown connection 0:{}", id)),
+                        ),
+                        Command::Set(..) => Ok(Reply::Replaced(1)),
+                        _ => Err(ControlError::Failed("unexpected command".to_string())),
+                    };
+                    let _ = request.reply.send(reply);
+                }
+            }
+        });
+        let phases = vec![Phase::parse("slow bandwidth:10,10,10").unwrap()];
+        Controller::new(vec![(0, sender)], phases)
+    }
+
+    fn status(result: Result<Value, Error>) -> u16 {
+        match result {
+            Ok(_) => 200,
+            Err((status, _)) => status,
+        }
+    }
+
+    #[test]
+    fn route_requests() {
+        let controller = controller();
+        let route = |method: Method, url: &str, body: &str| route(&controller, &method, url, body);
+
+        assert_eq!(
+            route(Method::Get, "/models", "").unwrap(),
+            json!({ "uplink": [], "downlink": ["random:1,0,0"] })
+        );
+        assert_eq!(
+            route(Method::Put, "/models", r#"{"uplink": ["random:5,0,0"]}"#).unwrap(),
+            json!({ "replaced": 1 })
+        );
+        assert_eq!(
+            route(Method::Post, "/phases/slow", "").unwrap(),
+            json!({ "replaced": 2 })
+        );
+        assert_eq!(
+            route(Method::Put, "/connections/0:1/models", "[]").unwrap(),
+            json!({ "replaced": 1 })
+        );
+        assert_eq!(
+            route(Method::Get, "/phases", "").unwrap()[0]["uplink"],
+            json!(["bandwidth:10,10,10"])
+        );
+
+        // unknown endpoints, phases and connections
+        assert_eq!(status(route(Method::Get, "/unknown", "")), 404);
+        assert_eq!(status(route(Method::Delete, "/models", "")), 404);
+        assert_eq!(status(route(Method::Post, "/phases/fast", "")), 404);
+        assert_eq!(
+            status(route(Method::Put, "/connections/0:2/models", "[]")),
+            404
+        );
+        assert_eq!(
+            status(route(Method::Put, "/connections/1:1/models", "[]")),
+            404
+        );
+        // invalid bodies
+        assert_eq!(status(route(Method::Put, "/models", "{}")), 400);
+        assert_eq!(status(route(Method::Put, "/models", "uplink")), 400);
+        assert_eq!(
+            status(route(
+                Method::Put,
+                "/models",
+                r#"{"uplink": "random:5,0,0"}"#
+            )),
+            400
+        );
+        assert_eq!(
+            status(route(
+                Method::Put,
+                "/models",
+                r#"{"uplink": ["unknown:1"]}"#
+            )),
+            400
+        );
+        assert_eq!(
+            status(route(
+                Method::Put,
+                "/connections/0:1/models",
+                r#"["pattern_file:/etc/shadow"]"#
+            )),
+            400
+        );
+        assert_eq!(
+            status(route(Method::Put, "/connections/uplink/models", "[]")),
+            400
+        );
+        // internal failure
+        assert_eq!(status(route(Method::Post, "/pause", "")), 500);
+    }
+
+    #[test]
+    fn stopped_scheduler_is_unavailable() {
+        let (sender, _) = mpsc::channel();
+        let controller = Controller::new(vec![(0, sender)], Vec::new());
+        let result = route(&controller, &Method::Get, "/counters", "");
+        assert_eq!(
+            result.err().unwrap(),
+            (503, "queue 0: scheduler has stopped".to_string())
+        );
+    }
+}
@@ -1,15 +1,58 @@
+mod capture;
 mod config;
+mod control;
+mod control_socket;
+mod firewall;
+#[cfg(feature = "http_api")]
+mod http_api;
 mod logging;
+mod metrics;
+mod netem;
 mod nfqueue_degrader;
 mod nfqueue_wrapper;
+mod packet_budget;
+mod profile;
 mod protocol;
 mod queuing_model;
+mod scenario;
+mod scheduler;
+mod shutdown;
+mod target_filter;
+mod trace;
 
 fn main() {
     println!("Start degrader");
     logging::init(log::LevelFilter::Debug);
 
-    let config = config::Config::from_cli();
+    let config = config::Config::from_cli().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    log::set_max_level(config.log_level.to_level_filter());
+    shutdown::install_handler();
+    if let Some(path) = &config.capture {
+        capture::start(path).unwrap_or_else(|e| {
+            eprintln!("failed to start capture: {}", e);
+            std::process::exit(1);
+        });
+    }
+    if let Some((path, format)) = &config.trace {
+        trace::start(path, *format).unwrap_or_else(|e| {
+            eprintln!("failed to start trace: {}", e);
+            std::process::exit(1);
+        });
+    }
+    // removes the rule again when the degrader stops, installed last as exiting on a
+    // failure skips the guard and would leave the rule behind
+    let _firewall_rule = config.firewall_rule.clone().map(|rule| {
+        firewall::install(rule).unwrap_or_else(|e| {
+            eprintln!("failed to install firewall rule: {}", e);
+            std::process::exit(1);
+        })
+    });
     let degrader = nfqueue_degrader::NfqueueDegrader::new(config);
     degrader.start();
+    capture::finish();
+    trace::finish();
+    println!("Stop degrader");
 }
@@ -0,0 +1,401 @@
+use crate::control::{Command, Controller, Reply};
+use crate::nfqueue_degrader::Summary;
+use crate::nfqueue_wrapper::{default_verdict_count, enobufs_count};
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Why a packet was dropped, and by which model
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DropReason {
+    /// random loss
+    RandomLoss,
+    /// drop entry of the pattern file
+    PatternLoss,
+    /// loss in a burst of the gilbert elliott model
+    BurstLoss,
+    /// bandwidth model buffer full
+    BufferFull,
+    /// global packet budget exhausted
+    Budget,
+    /// evicted from its queue for a newer packet, overflow policy drop_oldest
+    Evicted,
+}
+
+const DROP_REASONS: [DropReason; 6] = [
+    DropReason::RandomLoss,
+    DropReason::PatternLoss,
+    DropReason::BurstLoss,
+    DropReason::BufferFull,
+    DropReason::Budget,
+    DropReason::Evicted,
+];
+
+impl DropReason {
+    // (reason, model) labels
+    fn labels(self) -> (&'static str, &'static str) {
+        match self {
+            DropReason::RandomLoss => ("loss", "random"),
+            DropReason::PatternLoss => ("loss", "pattern_file"),
+            DropReason::BurstLoss => ("loss", "gilbert_elliott"),
+            DropReason::BufferFull => ("buffer_full", "bandwidth"),
+            DropReason::Budget => ("budget", "none"),
+            DropReason::Evicted => ("evicted", "none"),
+        }
+    }
+}
+
+static DROPPED: [AtomicU64; DROP_REASONS.len()] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+
+/// Counts a packet dropped by the degrader
+pub fn count_drop(reason: DropReason) {
+    DROPPED[reason as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Metrics of a queue and its connections as reported by its scheduler
+pub struct QueueMetrics {
+    pub summary: Summary,
+    /// how late the scheduler woke up for due packets
+    pub lag: Lag,
+    pub connections: Vec<ConnectionMetrics>,
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct Lag {
+    pub total: Duration,
+    pub count: u64,
+    pub max: Duration,
+}
+
+impl Lag {
+    pub fn record(&mut self, lag: Duration) {
+        self.total += lag;
+        self.count += 1;
+        self.max = self.max.max(lag);
+    }
+}
+
+pub struct ConnectionMetrics {
+    pub id: usize,
+    pub direction: String,
+    pub source: String,
+    pub destination: String,
+    pub queued: usize,
+    /// time from arrival to release of the released packets
+    pub sojourn_total: Duration,
+    pub released: u64,
+    /// fill of the token buckets of the bandwidth models, in chain order
+    pub token_fill: Vec<f64>,
+}
+
+/// Serves the metrics in Prometheus text format on its own thread
+pub fn serve(addr: &str, controller: Arc<Controller>) -> Result<(), String> {
+    let listener =
+        TcpListener::bind(addr).map_err(|e| format!("failed to listen on {}: {}", addr, e))?;
+    std::thread::Builder::new()
+        .name("metrics".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let result = stream.and_then(|stream| handle_scrape(stream, &controller));
+                if let Err(e) = result {
+                    log::warn!("metrics: {}", e);
+                }
+            }
+        })
+        .map_err(|e| format!("failed to spawn metrics thread: {}", e))?;
+    log::info!("metrics available on http://{}/metrics", addr);
+    Ok(())
+}
+
+fn handle_scrape(stream: TcpStream, controller: &Controller) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // skip the headers
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let (status, body) = match request_line.split_whitespace().nth(1) {
+        Some("/metrics") => ("200 OK", render(controller)),
+        _ => ("404 Not Found", "metrics are at /metrics\n".to_string()),
+    };
+    write!(
+        &stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+fn render(controller: &Controller) -> String {
+    let queues: Vec<(u16, QueueMetrics)> = match controller.execute(&Command::Metrics) {
+        Ok(replies) => replies
+            .into_iter()
+            .filter_map(|(queue_num, reply)| match reply {
+                Ok(Reply::Metrics(metrics)) => Some((queue_num, metrics)),
+                Ok(_) => None,
+                Err(e) => {
+                    log::warn!("no metrics of queue {}: {}", queue_num, e);
+                    None
+                }
+            })
+            .collect(),
+        Err(e) => {
+            log::warn!("no metrics: {}", e);
+            Vec::new()
+        }
+    };
+    format_metrics(&queues)
+}
+
+// text exposition format, samples are (suffix and labels, value)
+fn format_metrics(queues: &[(u16, QueueMetrics)]) -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, String)>| {
+        let _ = writeln!(out, "# HELP nfqueue_degrader_{} {}", name, help);
+        let _ = writeln!(out, "# TYPE nfqueue_degrader_{} {}", name, kind);
+        for (labels, value) in samples {
+            let _ = writeln!(out, "nfqueue_degrader_{}{} {}", name, labels, value);
+        }
+    };
+    let per_queue = |value: &dyn Fn(&QueueMetrics) -> String| {
+        queues
+            .iter()
+            .map(|(queue_num, metrics)| (format!("{{queue=\"{}\"}}", queue_num), value(metrics)))
+            .collect::<Vec<_>>()
+    };
+    let per_connection = |value: &dyn Fn(&ConnectionMetrics) -> String| {
+        queues
+            .iter()
+            .flat_map(|(queue_num, metrics)| {
+                metrics.connections.iter().map(move |connection| {
+                    (
+                        connection_labels(*queue_num, connection, ""),
+                        value(connection),
+                    )
+                })
+            })
+            .collect::<Vec<_>>()
+    };
+
+    metric(
+        "packets_received_total",
+        "counter",
+        "packets received from the kernel",
+        per_queue(&|m| m.summary.received.to_string()),
+    );
+    metric(
+        "packets_released_total",
+        "counter",
+        "packets accepted after degradation",
+        per_queue(&|m| m.summary.released.to_string()),
+    );
+    metric(
+        "packets_accepted_undegraded_total",
+        "counter",
+        "packets accepted without degradation (paused, flushed, budget overflow, shutdown)",
+        per_queue(&|m| m.summary.accepted.to_string()),
+    );
+    metric(
+        "bytes_received_total",
+        "counter",
+        "bytes on the wire of the received packets",
+        per_queue(&|m| m.summary.received_bytes.to_string()),
+    );
+    metric(
+        "bytes_released_total",
+        "counter",
+        "bytes on the wire of the packets accepted after degradation",
+        per_queue(&|m| m.summary.released_bytes.to_string()),
+    );
+    metric(
+        "packets_dropped_total",
+        "counter",
+        "packets dropped by reason and model",
+        DROP_REASONS
+            .iter()
+            .map(|reason| {
+                let (reason_label, model) = reason.labels();
+                (
+                    format!("{{reason=\"{}\",model=\"{}\"}}", reason_label, model),
+                    DROPPED[*reason as usize]
+                        .load(Ordering::Relaxed)
+                        .to_string(),
+                )
+            })
+            .collect(),
+    );
+    metric(
+        "packets_without_verdict_total",
+        "counter",
+        "packets that got the default verdict",
+        vec![(String::new(), default_verdict_count().to_string())],
+    );
+    metric(
+        "socket_buffer_overruns_total",
+        "counter",
+        "socket buffer overruns (ENOBUFS) of the nfqueue sockets, each losing packets",
+        vec![(String::new(), enobufs_count().to_string())],
+    );
+    metric(
+        "scheduler_lag_seconds",
+        "summary",
+        "time the scheduler woke up later than the next due packet",
+        summary(
+            per_queue(&|m| seconds(m.lag.total)),
+            per_queue(&|m| m.lag.count.to_string()),
+        ),
+    );
+    metric(
+        "scheduler_max_lag_seconds",
+        "gauge",
+        "max. time the scheduler woke up later than the next due packet",
+        per_queue(&|m| seconds(m.lag.max)),
+    );
+    metric(
+        "connection_queued_packets",
+        "gauge",
+        "packets currently held by the models of a connection",
+        per_connection(&|c| c.queued.to_string()),
+    );
+    metric(
+        "connection_sojourn_seconds",
+        "summary",
+        "time the released packets of a connection spent in the degrader",
+        summary(
+            per_connection(&|c| seconds(c.sojourn_total)),
+            per_connection(&|c| c.released.to_string()),
+        ),
+    );
+    metric(
+        "token_bucket_fill_ratio",
+        "gauge",
+        "available tokens of a bandwidth model relative to its burst size",
+        queues
+            .iter()
+            .flat_map(|(queue_num, metrics)| {
+                metrics.connections.iter().flat_map(move |connection| {
+                    connection
+                        .token_fill
+                        .iter()
+                        .enumerate()
+                        .map(move |(index, fill)| {
+                            let model = format!(",model=\"{}\"", index);
+                            (
+                                connection_labels(*queue_num, connection, &model),
+                                fill.to_string(),
+                            )
+                        })
+                })
+            })
+            .collect(),
+    );
+    out
+}
+
+fn connection_labels(queue_num: u16, connection: &ConnectionMetrics, extra: &str) -> String {
+    format!(
+        "{{connection=\"{}:{}\",direction=\"{}\",source=\"{}\",destination=\"{}\"{}}}",
+        queue_num,
+        connection.id,
+        connection.direction,
+        connection.source,
+        connection.destination,
+        extra
+    )
+}
+
+// samples of a summary without quantiles from the sums and counts of each label set
+fn summary(sums: Vec<(String, String)>, counts: Vec<(String, String)>) -> Vec<(String, String)> {
+    sums.into_iter()
+        .zip(counts)
+        .flat_map(|((labels, sum), (_, count))| {
+            [
+                (format!("_sum{}", labels), sum),
+                (format!("_count{}", labels), count),
+            ]
+        })
+        .collect()
+}
+
+fn seconds(duration: Duration) -> String {
+    duration.as_secs_f64().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_summaries_as_one_family() {
+        let mut lag = Lag::default();
+        lag.record(Duration::from_millis(1));
+        lag.record(Duration::from_millis(3));
+        let connection = ConnectionMetrics {
+            id: 1,
+            direction: "uplink".to_string(),
+            source: "10.0.0.1:5000".to_string(),
+            destination: "10.0.0.2:6000".to_string(),
+            queued: 2,
+            sojourn_total: Duration::from_millis(250),
+            released: 5,
+            token_fill: vec![0.5],
+        };
+        let queues = vec![(
+            3,
+            QueueMetrics {
+                summary: Summary::default(),
+                lag,
+                connections: vec![connection],
+            },
+        )];
+        let text = format_metrics(&queues);
+
+        assert!(text.contains(
+            "# HELP nfqueue_degrader_scheduler_lag_seconds time the scheduler woke up later than the next due packet
+# TYPE nfqueue_degrader_scheduler_lag_seconds summary
+nfqueue_degrader_scheduler_lag_seconds_sum{queue=\"3\"} 0.004
+nfqueue_degrader_scheduler_lag_seconds_count{queue=\"3\"} 2
+# HELP nfqueue_degrader_scheduler_max_lag_seconds max. time the scheduler woke up later than the next due packet
+# TYPE nfqueue_degrader_scheduler_max_lag_seconds gauge
+nfqueue_degrader_scheduler_max_lag_seconds{queue=\"3\"} 0.003
+"
+        ));
+        let labels = "{connection=\"3:1\",direction=\"uplink\",source=\"10.0.0.1:5000\",destination=\"10.0.0.2:6000\"}";
+        assert!(text.contains(&format!(
+            "# TYPE nfqueue_degrader_connection_sojourn_seconds summary
+nfqueue_degrader_connection_sojourn_seconds_sum{} 0.25
+nfqueue_degrader_connection_sojourn_seconds_count{} 5
+",
+            labels, labels
+        )));
+
+        // each family has one type, no family is named like the samples of a summary
+        let families: Vec<&str> = text
+            .lines()
+            .filter_map(|line| line.strip_prefix("# TYPE "))
+            .map(|line| line.split(' ').next().unwrap())
+            .collect();
+        let mut unique = families.clone();
+        unique.sort_unstable();
+        unique.dedup();
+        assert_eq!(unique.len(), families.len());
+        assert!(families
+            .iter()
+            .all(|name| !name.ends_with("_sum") && !name.ends_with("_count")));
+    }
+}
@@ -0,0 +1,275 @@
+use crate::config::{parse_model_spec, QueuingModelConfig};
+use std::iter::Peekable;
+use std::str::SplitWhitespace;
+
+// netem's default queue limit in packets and the packet size to convert it to a buffer size
+const DEFAULT_LIMIT: u64 = 1000;
+const PACKET_SIZE: u64 = 1500;
+// netem sends packets one by one at the rate, the bucket holds about one packet
+const BURST_SIZE: u64 = 2;
+
+type Tokens<'a> = Peekable<SplitWhitespace<'a>>;
+
+/// Translates the options of a netem qdisc, e.g. `delay 100ms 20ms loss 0.3% rate 1mbit`,
+/// into a model chain: loss, then rate and limit as bandwidth model, then delay. A
+/// leading `tc qdisc add dev <dev> root netem` is skipped. Options the models can't
+/// reproduce (correlation, delay distributions, duplicate, corrupt, reorder, ...) are
+/// an error.
+pub fn parse(spec: &str) -> Result<Vec<QueuingModelConfig>, String> {
+    let options = match spec.split_once("netem ") {
+        Some((_, options)) => options,
+        None => spec,
+    };
+    let mut tokens = options.split_whitespace().peekable();
+
+    let mut delay = None;
+    let mut loss = None;
+    let mut rate = None;
+    let mut limit = None;
+    while let Some(option) = tokens.next() {
+        match option {
+            "delay" => set(&mut delay, option, parse_delay(&mut tokens)?)?,
+            "loss" | "drop" => set(&mut loss, option, parse_loss(&mut tokens)?)?,
+            "rate" => set(&mut rate, option, parse_rate(&mut tokens)?)?,
+            "limit" => {
+                let packets = tokens
+                    .next()
+                    .and_then(|value| value.parse::<u64>().ok())
+                    .filter(|packets| *packets > 0)
+                    .ok_or("limit expects a number of packets larger 0")?;
+                set(&mut limit, option, packets)?
+            }
+            "distribution" => return Err("distribution must follow delay".to_string()),
+            "duplicate" | "corrupt" | "reorder" | "gap" | "slot" | "ecn" => {
+                return Err(format!("{} is not supported", option))
+            }
+            _ => return Err(format!("unknown netem option {}", option)),
+        }
+    }
+
+    let mut specs = Vec::new();
+    if let Some(loss) = loss {
+        specs.push(loss);
+    }
+    match (rate, limit) {
+        (Some(rate), limit) => {
+            let buffer = (limit.unwrap_or(DEFAULT_LIMIT) * PACKET_SIZE).div_ceil(1024);
+            specs.push(format!(
+                "bandwidth:{},{},{}",
+                rate,
+                BURST_SIZE,
+                buffer.max(BURST_SIZE)
+            ));
+        }
+        (None, Some(_)) => {
+            return Err("limit is only supported together with rate, as its buffer".to_string())
+        }
+        (None, None) => {}
+    }
+    if let Some((min, max)) = delay {
+        specs.push(format!("random:0,{},{}", min, max));
+    }
+    specs.iter().map(|spec| parse_model_spec(spec)).collect()
+}
+
+fn set<T>(option: &mut Option<T>, name: &str, value: T) -> Result<(), String> {
+    if option.replace(value).is_some() {
+        return Err(format!("{} given twice", name));
+    }
+    Ok(())
+}
+
+// next token if it is a number, netem's optional values
+fn next_number<'a>(tokens: &mut Tokens<'a>) -> Option<&'a str> {
+    tokens.next_if(|token| token.starts_with(|c: char| c.is_ascii_digit() || c == '.' || c == '-'))
+}
+
+// optional correlation, only 0 is supported
+fn no_correlation(tokens: &mut Tokens, option: &str) -> Result<(), String> {
+    match next_number(tokens) {
+        Some(value) if percent(value)? == 0.0 => Ok(()),
+        Some(_) => Err(format!("correlation of {} is not supported", option)),
+        None => Ok(()),
+    }
+}
+
+// `delay <time> [<jitter> [<correlation>]] [distribution <name>]`, as min. and max. delay in ms
+fn parse_delay(tokens: &mut Tokens) -> Result<(u64, u64), String> {
+    let delay = tokens.next().ok_or("delay expects a time")?;
+    let delay = millis(delay)?;
+    let jitter = match next_number(tokens) {
+        Some(jitter) => millis(jitter)?,
+        None => 0,
+    };
+    no_correlation(tokens, "delay")?;
+    if tokens.next_if_eq(&"distribution").is_some() {
+        return Err(format!(
+            "distribution {} is not supported, jitter without distribution is uniform",
+            tokens.next().unwrap_or_default()
+        ));
+    }
+    Ok((delay.saturating_sub(jitter), delay + jitter))
+}
+
+// `loss [random] <percent> [<correlation>]` or `loss gemodel <p> [<r> [<1-h> [<1-k>]]]`
+fn parse_loss(tokens: &mut Tokens) -> Result<String, String> {
+    match tokens.next() {
+        Some("gemodel") => {
+            let p = percent(tokens.next().ok_or("loss gemodel expects p")?)?;
+            let mut optional = || next_number(tokens).map(percent).transpose();
+            let r = optional()?.unwrap_or(100.0 - p);
+            let bad_loss = optional()?.unwrap_or(100.0);
+            let good_loss = optional()?.unwrap_or(0.0);
+            Ok(format!(
+                "gilbert_elliott:{},{},{},{}",
+                p, r, bad_loss, good_loss
+            ))
+        }
+        Some("state") => Err("loss state is not supported, use loss gemodel".to_string()),
+        Some(token) => {
+            let loss = match token {
+                "random" => tokens.next().ok_or("loss random expects a percentage")?,
+                loss => loss,
+            };
+            let loss = percent(loss)?;
+            no_correlation(tokens, "loss")?;
+            // the random model only has whole percents, the gilbert elliott model
+            // without a bad state loses any percentage
+            if loss.fract() == 0.0 {
+                Ok(format!("random:{},0,0", loss))
+            } else {
+                Ok(format!("gilbert_elliott:0,100,0,{}", loss))
+            }
+        }
+        None => Err("loss expects a percentage".to_string()),
+    }
+}
+
+// `rate <rate>` in KBps, packet and cell overheads are not supported
+fn parse_rate(tokens: &mut Tokens) -> Result<u64, String> {
+    let rate = tokens.next().ok_or("rate expects a rate")?;
+    let split = rate
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(rate.len());
+    let (value, unit) = rate.split_at(split);
+    let value = value
+        .parse::<f64>()
+        .map_err(|_| format!("invalid rate {}", rate))?;
+    // bits per second without unit, as in tc
+    let bytes = match unit.to_ascii_lowercase().as_str() {
+        "" | "bit" => value / 8.0,
+        "kbit" => value * 1e3 / 8.0,
+        "mbit" => value * 1e6 / 8.0,
+        "gbit" => value * 1e9 / 8.0,
+        "kibit" => value * 1024.0 / 8.0,
+        "mibit" => value * 1024.0 * 1024.0 / 8.0,
+        "gibit" => value * 1024.0 * 1024.0 * 1024.0 / 8.0,
+        "bps" => value,
+        "kbps" => value * 1e3,
+        "mbps" => value * 1e6,
+        "gbps" => value * 1e9,
+        "kibps" => value * 1024.0,
+        "mibps" => value * 1024.0 * 1024.0,
+        "gibps" => value * 1024.0 * 1024.0 * 1024.0,
+        _ => return Err(format!("unknown unit of rate {}", rate)),
+    };
+    if next_number(tokens).is_some() {
+        return Err("packet and cell overhead of rate are not supported".to_string());
+    }
+    let kbps = (bytes / 1024.0).round() as u64;
+    if kbps == 0 {
+        return Err(format!("rate {} is below 1 KBps", rate));
+    }
+    Ok(kbps)
+}
+
+// time in whole milliseconds, microseconds without unit, as in tc
+fn millis(time: &str) -> Result<u64, String> {
+    let split = time
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(time.len());
+    let (value, unit) = time.split_at(split);
+    let value = value
+        .parse::<f64>()
+        .map_err(|_| format!("invalid time {}", time))?;
+    let millis = match unit {
+        "s" | "sec" | "secs" => value * 1000.0,
+        "ms" | "msec" | "msecs" => value,
+        "" | "us" | "usec" | "usecs" => value / 1000.0,
+        _ => return Err(format!("unknown unit of time {}", time)),
+    };
+    if millis.fract() != 0.0 {
+        return Err(format!("time {} is not a whole number of ms", time));
+    }
+    Ok(millis as u64)
+}
+
+fn percent(value: &str) -> Result<f64, String> {
+    value
+        .strip_suffix('%')
+        .unwrap_or(value)
+        .parse::<f64>()
+        .ok()
+        .filter(|percent| (0.0..=100.0).contains(percent))
+        .ok_or_else(|| format!("invalid percentage {}", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn translate_netem_options() {
+        let specs = |spec: &str| -> Vec<String> {
+            parse(spec)
+                .unwrap()
+                .iter()
+                .map(|model| model.to_string())
+                .collect()
+        };
+        assert_eq!(
+            specs("delay 100ms 20ms loss 0.3% rate 1mbit limit 1000"),
+            vec![
+                "gilbert_elliott:0,100,0,0.3",
+                "bandwidth:122,2,1465",
+                "random:0,80,120"
+            ]
+        );
+        assert_eq!(
+            specs("tc qdisc add dev eth0 root netem loss gemodel 1% 25% delay 1s 10ms 0%"),
+            vec!["gilbert_elliott:1,25,100,0", "random:0,990,1010"]
+        );
+        assert_eq!(
+            specs("loss random 2 rate 8kbit"),
+            vec!["random:2,0,0", "bandwidth:1,2,1465"]
+        );
+
+        let error = |spec: &str| parse(spec).err().unwrap();
+        assert_eq!(
+            error("delay 100ms 20ms 25% distribution normal loss 0.3% 25% duplicate 1% reorder 25% 50% rate 1mbit limit 1000"),
+            "correlation of delay is not supported"
+        );
+        assert_eq!(
+            error("delay 100ms 20ms distribution normal"),
+            "distribution normal is not supported, jitter without distribution is uniform"
+        );
+        assert_eq!(
+            error("loss 0.3% 25%"),
+            "correlation of loss is not supported"
+        );
+        assert_eq!(error("duplicate 1%"), "duplicate is not supported");
+        assert_eq!(
+            error("rate 1mbit 20"),
+            "packet and cell overhead of rate are not supported"
+        );
+        assert_eq!(
+            error("delay 500us"),
+            "time 500us is not a whole number of ms"
+        );
+        assert_eq!(
+            error("limit 100"),
+            "limit is only supported together with rate, as its buffer"
+        );
+        assert_eq!(error("delay 1ms delay 2ms"), "delay given twice");
+    }
+}
@@ -1,77 +1,552 @@
-use crate::config;
+use crate::config::{self, QueuingModelConfig};
+use crate::control::{self, Command, ConnectionState, ControlError, Controller, Reply, Selector};
+use crate::metrics::{self, ConnectionMetrics, DropReason, Lag, QueueMetrics};
 use crate::nfqueue_wrapper::*;
+use crate::packet_budget::{OverflowPolicy, PacketBudget};
 use crate::protocol::*;
 use crate::queuing_model::queuing_model_chain::QueuingModelChain;
-use crate::queuing_model::QueuingModel;
-use std::collections::HashMap;
-use std::sync::mpsc;
+use crate::queuing_model::{Action, Decision, QueuingModel};
+use crate::scheduler::Scheduler;
+use crate::shutdown::{self, ShutdownMode};
+use crate::target_filter::TargetFilter;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
 use std::time::{Duration, Instant};
 
+// max. time the scheduler sleeps before checking for a shutdown request
+const SHUTDOWN_POLL: Duration = Duration::from_millis(100);
+
+/// Input of a scheduler thread
+pub enum Event {
+    /// packet and whether it belongs to the targeted user/process
+    Packet(NfqPacket, bool),
+    Control(control::Request),
+}
+
 fn queue_callback(packet: NfqPacket, state: &mut State) {
-    state.sender.send(packet).unwrap();
+    // decided on the receiving thread, so the scheduler never waits for an owner lookup
+    let targeted = match state.target_filter.as_ref() {
+        Some(filter) => {
+            let direction =
+                Direction::from_hook(packet.hook, packet.indev, packet.outdev, state.uplink_dev);
+            let info = ProtocolInfo::from_ipv4_header(packet.get_payload());
+            filter.matches(&info, direction, packet.uid)
+        }
+        None => true,
+    };
+    // the scheduler has finished on shutdown, new packets pass undegraded
+    if let Err(mpsc::SendError(Event::Packet(packet, _))) =
+        state.sender.send(Event::Packet(packet, targeted))
+    {
+        packet.set_verdict(Verdict::Accept);
+    }
 }
 
-fn thread_func(packet_rx: mpsc::Receiver<NfqPacket>, cfg: config::Config) {
-    let clock = Instant::now();
-    let mut connection_queues = HashMap::new();
+/// Packet counts of one queue
+#[derive(Clone, Default)]
+pub struct Summary {
+    pub received: u64,
+    /// released by the models
+    pub released: u64,
+    /// accepted without degradation (budget overflow, shutdown, pause, flush)
+    pub accepted: u64,
+    /// size on the wire of the received and released packets
+    pub received_bytes: u64,
+    pub released_bytes: u64,
+}
+
+impl Summary {
+    pub fn add(&mut self, other: &Summary) {
+        self.received += other.received;
+        self.released += other.released;
+        self.accepted += other.accepted;
+        self.received_bytes += other.received_bytes;
+        self.released_bytes += other.released_bytes;
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.received.saturating_sub(self.released + self.accepted)
+    }
+}
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} packets received, {} released, {} accepted without degradation, {} dropped",
+            self.received,
+            self.released,
+            self.accepted,
+            self.dropped()
+        )
+    }
+}
+
+// direction, targeted by --user/--process, connection (default if not per connection)
+type ConnectionKey = (Direction, bool, ProtocolInfo);
+
+// reserves budget for a packet, by evicting the packets which arrived first, of all
+// connections of the queue, if configured. false if the budget is exhausted.
+fn reserve_budget(
+    packet: &mut NfqPacket,
+    scheduler: &mut Scheduler<ConnectionKey, QueuingModelChain>,
+    budget: &Arc<PacketBudget>,
+    policy: OverflowPolicy,
+) -> bool {
     loop {
-        let now = clock.elapsed();
-        if let Ok(p) = packet_rx.recv_timeout(Duration::from_millis(1)) {
-            let protocol_info = if cfg.apply_per_connection {
-                ProtocolInfo::from_ipv4_header(p.get_payload())
-            } else {
-                ProtocolInfo::default()
+        if let Some(guard) = budget.reserve(packet.payload.len()) {
+            packet.budget = Some(guard);
+            return true;
+        }
+        if policy != OverflowPolicy::DropOldest {
+            return false;
+        }
+        match scheduler.evict_oldest() {
+            Some(mut oldest) => {
+                metrics::count_drop(DropReason::Evicted);
+                oldest.decisions.push(Decision::new("budget", Action::Drop));
+                oldest.set_verdict(Verdict::Drop)
+            }
+            None => return false,
+        }
+    }
+}
+
+// degradation settings of a scheduler that can be changed through the control socket
+struct Controls {
+    paused: bool,
+    uplink_models: Vec<QueuingModelConfig>,
+    downlink_models: Vec<QueuingModelConfig>,
+}
+
+impl Controls {
+    fn models(&self, direction: Direction) -> &[QueuingModelConfig] {
+        match direction {
+            Direction::Uplink => &self.uplink_models,
+            Direction::Downlink => &self.downlink_models,
+        }
+    }
+}
+
+fn handle_control(
+    command: Command,
+    queue_num: u16,
+    scheduler: &mut Scheduler<ConnectionKey, QueuingModelChain>,
+    controls: &mut Controls,
+    summary: &mut Summary,
+    lag: &Lag,
+) -> Result<Reply, ControlError> {
+    let selector = command.selector();
+    let unknown_connection = |count: usize| match selector {
+        Selector::Connection(queue, id) if count == 0 => Err(ControlError::NotFound(format!(
+            "unknown connection {}:{}",
+            queue, id
+        ))),
+        _ => Ok(()),
+    };
+
+    match command {
+        Command::List | Command::Show(_) => {
+            let connections: Vec<_> = scheduler
+                .connections()
+                .into_iter()
+                .filter(|(id, (direction, _, _), _)| selector.matches(queue_num, *id, *direction))
+                .map(
+                    |(id, (direction, targeted, connection), chain)| ConnectionState {
+                        id,
+                        direction: *direction,
+                        targeted: *targeted,
+                        connection: *connection,
+                        models: chain.to_string(),
+                    },
+                )
+                .collect();
+            unknown_connection(connections.len())?;
+            Ok(Reply::Connections(connections))
+        }
+        Command::Set(_, models) => {
+            let mut count = 0;
+            // untargeted connections are only changed if selected explicitly
+            scheduler.update(|id, (direction, targeted, _), chain| {
+                let explicit = matches!(selector, Selector::Connection(..));
+                if selector.matches(queue_num, id, *direction) && (*targeted || explicit) {
+                    chain.replace(&models);
+                    count += 1;
+                }
+            });
+            unknown_connection(count)?;
+            match selector {
+                Selector::All => {
+                    controls.uplink_models = models.clone();
+                    controls.downlink_models = models;
+                }
+                Selector::Direction(Direction::Uplink) => controls.uplink_models = models,
+                Selector::Direction(Direction::Downlink) => controls.downlink_models = models,
+                Selector::Connection(..) => {}
+            }
+            Ok(Reply::Replaced(count))
+        }
+        Command::Models => Ok(Reply::Models {
+            uplink: controls.uplink_models.clone(),
+            downlink: controls.downlink_models.clone(),
+        }),
+        Command::Counters => Ok(Reply::Counters(summary.clone())),
+        Command::Metrics => {
+            let connections = scheduler
+                .connections()
+                .into_iter()
+                .map(|(id, (direction, _, connection), chain)| {
+                    let stats = chain.stats();
+                    ConnectionMetrics {
+                        id,
+                        direction: direction.to_string(),
+                        source: format!(
+                            "{}:{}",
+                            connection.source_ip_to_string(),
+                            connection.source_port
+                        ),
+                        destination: format!(
+                            "{}:{}",
+                            connection.destination_ip_to_string(),
+                            connection.destination_port
+                        ),
+                        queued: stats.queued,
+                        sojourn_total: stats.total_delay,
+                        released: stats.released,
+                        token_fill: chain.token_fills(),
+                    }
+                })
+                .collect();
+            Ok(Reply::Metrics(QueueMetrics {
+                summary: summary.clone(),
+                lag: *lag,
+                connections,
+            }))
+        }
+        // resolved by the controller
+        Command::Phases | Command::Phase(_) => {
+            Err(ControlError::Failed("unexpected phase command".to_string()))
+        }
+        Command::Pause | Command::Resume => {
+            controls.paused = matches!(command, Command::Pause);
+            Ok(Reply::Paused(controls.paused))
+        }
+        Command::Flush(_) => {
+            let mut packets = Vec::new();
+            let mut connections = 0;
+            scheduler.update(|id, (direction, _, _), chain| {
+                if selector.matches(queue_num, id, *direction) {
+                    packets.append(&mut chain.flush());
+                    connections += 1;
+                }
+            });
+            unknown_connection(connections)?;
+            let reply = Reply::Flushed {
+                packets: packets.len(),
+                connections,
             };
+            summary.accepted += packets.len() as u64;
+            set_verdict_batch(packets, Verdict::Accept);
+            Ok(reply)
+        }
+    }
+}
 
-            log::debug!("packet received for connection: {}", protocol_info);
+fn log_stats(queue_num: u16, scheduler: &Scheduler<ConnectionKey, QueuingModelChain>) {
+    for (id, (direction, _, connection), chain) in scheduler.connections() {
+        log::info!(
+            "{} connection {}:{} {}: {}",
+            direction,
+            queue_num,
+            id,
+            connection,
+            chain.stats()
+        );
+        for (model, stats) in chain.model_stats() {
+            log::info!("    {}: {}", model, stats);
+        }
+    }
+}
+
+fn thread_func(
+    queue_num: u16,
+    event_rx: mpsc::Receiver<Event>,
+    cfg: Arc<config::Config>,
+    budget: Arc<PacketBudget>,
+) -> Summary {
+    let clock = Instant::now();
+    let mut summary = Summary::default();
+    let mut stopping = false;
+    let mut overflow_count: u64 = 0;
+    let mut lag = Lag::default();
+    let mut next_stats = cfg.stats_interval.unwrap_or_default();
+    let mut scheduler = Scheduler::new();
+    let mut controls = Controls {
+        paused: false,
+        uplink_models: cfg.models(Direction::Uplink).to_vec(),
+        downlink_models: cfg.models(Direction::Downlink).to_vec(),
+    };
+    loop {
+        let packets = scheduler.release(clock.elapsed());
+        summary.released += packets.len() as u64;
+        summary.released_bytes += packets.iter().map(|p| p.wire_len as u64).sum::<u64>();
+        set_verdict_batch(packets, Verdict::Accept);
+
+        if shutdown::requested() && !stopping {
+            stopping = true;
+            log::info!("shutdown requested, {:?} queued packets", cfg.shutdown_mode);
+        }
+        if stopping && (cfg.shutdown_mode == ShutdownMode::Flush || shutdown::forced()) {
+            let packets = scheduler.flush();
+            summary.accepted += packets.len() as u64;
+            set_verdict_batch(packets, Verdict::Accept);
+        }
+        if let Some(interval) = cfg.stats_interval {
+            if clock.elapsed() >= next_stats {
+                log_stats(queue_num, &scheduler);
+                next_stats = clock.elapsed() + interval;
+            }
+        }
+        if stopping && scheduler.next_deadline().is_none() {
+            if cfg.stats_interval.is_some() {
+                log_stats(queue_num, &scheduler);
+            }
+            break;
+        }
 
-            if connection_queues.get(&protocol_info).is_none() {
-                log::info!("add new packet queue for connection {}", protocol_info);
+        // sleep until the next packet is due or a new one arrives
+        let (timeout, wake_at) = match scheduler.next_deadline() {
+            Some(deadline) => match deadline.saturating_sub(clock.elapsed()) {
+                wait if wait <= SHUTDOWN_POLL => (wait, Some(deadline)),
+                _ => (SHUTDOWN_POLL, None),
+            },
+            None => (SHUTDOWN_POLL, None),
+        };
+        let (mut p, targeted) = match event_rx.recv_timeout(timeout) {
+            Ok(Event::Packet(p, targeted)) => (p, targeted),
+            Ok(Event::Control(request)) => {
+                let reply = handle_control(
+                    request.command,
+                    queue_num,
+                    &mut scheduler,
+                    &mut controls,
+                    &mut summary,
+                    &lag,
+                );
+                let _ = request.reply.send(reply);
+                continue;
             }
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                // woken up for a due packet, how late?
+                if let Some(deadline) = wake_at {
+                    lag.record(clock.elapsed().saturating_sub(deadline));
+                }
+                continue;
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        };
+        summary.received += 1;
+        summary.received_bytes += p.wire_len as u64;
+        if stopping || controls.paused {
+            summary.accepted += 1;
+            p.set_verdict(Verdict::Accept);
+            continue;
+        }
+        if p.gso {
+            let (segments, wire_len) = gso_segments(&p.payload, p.wire_len, cfg.mtu);
+            p.segments = segments;
+            p.wire_len = wire_len;
+        }
+
+        let direction = Direction::from_hook(p.hook, p.indev, p.outdev, cfg.uplink_dev);
 
-            let model_chain = connection_queues
-                .entry(protocol_info)
-                .or_insert_with(|| QueuingModelChain::new(&cfg.models));
-            model_chain.enqueue(p, now);
+        let mut protocol_info = ProtocolInfo::default();
+        if cfg.apply_per_connection {
+            protocol_info = ProtocolInfo::from_ipv4_header(p.get_payload());
         }
 
-        for packet_queue in connection_queues.values_mut() {
-            for p in packet_queue.dequeue(now) {
-                p.set_verdict(Verdict::Accept);
+        log::debug!(
+            "{} packet received for connection: {} (hook {}, in {}, out {}, segments {}, uid {:?}, gid {:?}, conntrack {})",
+            direction,
+            protocol_info,
+            p.hook,
+            p.indev,
+            p.outdev,
+            p.segments,
+            p.uid,
+            p.gid,
+            p.conntrack.map(|ct| ct.to_string()).unwrap_or_default()
+        );
+
+        // delays start when the kernel received the packet, not when it's scheduled here
+        let arrival = p.arrival.saturating_duration_since(clock);
+        // traffic of other users/processes passes an empty (forwarding) chain
+        let key = (direction, targeted, protocol_info);
+        if !reserve_budget(&mut p, &mut scheduler, &budget, cfg.overflow_policy) {
+            overflow_count += 1;
+            if overflow_count.is_power_of_two() {
+                log::warn!(
+                    "packet budget exhausted, {:?} ({} packets so far)",
+                    cfg.overflow_policy,
+                    overflow_count
+                );
             }
+            match cfg.overflow_policy {
+                OverflowPolicy::Accept => {
+                    summary.accepted += 1;
+                    p.set_verdict(Verdict::Accept)
+                }
+                _ => {
+                    metrics::count_drop(DropReason::Budget);
+                    p.decisions.push(Decision::new("budget", Action::Drop));
+                    p.set_verdict(Verdict::Drop)
+                }
+            }
+            continue;
         }
+        scheduler.enqueue(key, p, arrival, |key| {
+            log::info!(
+                "add new {} packet queue for {}connection {}",
+                key.0,
+                if targeted { "" } else { "untargeted " },
+                key.2
+            );
+            QueuingModelChain::new(if targeted {
+                controls.models(direction)
+            } else {
+                &[]
+            })
+        });
     }
+    summary
 }
 
 pub struct State {
-    sender: mpsc::Sender<NfqPacket>,
+    sender: mpsc::Sender<Event>,
+    target_filter: Option<TargetFilter>,
+    uplink_dev: Option<u32>,
+}
+
+// tells the queue thread to stop once the scheduler has finished, also if it panicked
+struct Finished(Arc<AtomicBool>);
+
+impl Drop for Finished {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+// one worker per nfqueue: the queue handle runs on the worker thread and hands
+// packets to its own scheduler thread. iptables --queue-balance hashes by flow,
+// so all packets of a connection are handled by the same worker.
+fn worker_func(
+    queue_num: u16,
+    (event_tx, event_rx): (mpsc::Sender<Event>, mpsc::Receiver<Event>),
+    cfg: Arc<config::Config>,
+    budget: Arc<PacketBudget>,
+) -> Summary {
+    let finished = Arc::new(AtomicBool::new(false));
+
+    let scheduler_cfg = Arc::clone(&cfg);
+    let scheduler_finished = Finished(Arc::clone(&finished));
+    let scheduler = std::thread::Builder::new()
+        .name(format!("scheduler-{}", queue_num))
+        .spawn(move || {
+            let _finished = scheduler_finished;
+            thread_func(queue_num, event_rx, scheduler_cfg, budget)
+        })
+        .expect("failed to spawn scheduler thread");
+
+    let state = State {
+        sender: event_tx,
+        target_filter: TargetFilter::new(cfg.target_user, cfg.target_process.clone()),
+        uplink_dev: cfg.uplink_dev,
+    };
+    let mut queue = NfQueueWrapper::new(state, queue_callback);
+    queue.open(queue_num, &cfg.queue_options);
+    // keeps receiving while the scheduler drains, until it has finished
+    queue.run_loop(&finished);
+
+    let summary = scheduler.join().unwrap_or_else(|_| {
+        log::error!("scheduler of queue {} terminated unexpectedly", queue_num);
+        Summary::default()
+    });
+    queue.close();
+    log::info!("queue {}: {}", queue_num, summary);
+    summary
 }
 
 pub struct NfqueueDegrader {
-    queue: NfQueueWrapper<State>,
-    config: config::Config,
-    receiver: mpsc::Receiver<NfqPacket>,
+    config: Arc<config::Config>,
 }
 
 impl NfqueueDegrader {
     pub fn new(conf: config::Config) -> Self {
-        let (packet_tx, packet_rx): (mpsc::Sender<NfqPacket>, _) = mpsc::channel();
-
         Self {
-            queue: NfQueueWrapper::new(State { sender: packet_tx }, queue_callback),
-            config: conf,
-            receiver: packet_rx,
+            config: Arc::new(conf),
         }
     }
 
-    pub fn start(mut self) {
-        let packet_rx = self.receiver;
-        let queue_num = self.config.queue_num;
-        let cfg = self.config;
-        std::thread::spawn(move || {
-            thread_func(packet_rx, cfg);
+    pub fn start(self) {
+        // shared by the workers of all queues
+        let budget =
+            PacketBudget::new(self.config.max_queued_bytes, self.config.max_queued_packets);
+        let (first_queue, last_queue) = self.config.queue_range;
+        let channels: Vec<_> = (first_queue..=last_queue)
+            .map(|queue_num| (queue_num, mpsc::channel()))
+            .collect();
+
+        let schedulers = channels
+            .iter()
+            .map(|(queue_num, (event_tx, _))| (*queue_num, event_tx.clone()))
+            .collect();
+        let controller = Arc::new(Controller::new(schedulers, self.config.phases.clone()));
+        // degradation keeps running if the control socket can't be set up
+        let _control_socket = self.config.control_socket.as_ref().and_then(|path| {
+            control::serve(path, Arc::clone(&controller))
+                .map_err(|e| log::error!("control socket not available: {}", e))
+                .ok()
         });
-        self.queue.open(queue_num);
-        self.queue.run_loop();
+        if let Some(addr) = &self.config.metrics {
+            if let Err(e) = metrics::serve(addr, Arc::clone(&controller)) {
+                log::error!("metrics not available: {}", e);
+                eprintln!("metrics not available: {}", e);
+            }
+        }
+        #[cfg(feature = "http_api")]
+        if let Some(addr) = &self.config.http_api {
+            if let Err(e) = crate::http_api::serve(addr, Arc::clone(&controller)) {
+                log::error!("REST api not available: {}", e);
+                eprintln!("REST api not available: {}", e);
+            }
+        }
+
+        let workers: Vec<_> = channels
+            .into_iter()
+            .map(|(queue_num, channel)| {
+                let cfg = Arc::clone(&self.config);
+                let budget = Arc::clone(&budget);
+                std::thread::Builder::new()
+                    .name(format!("nfqueue-{}", queue_num))
+                    .spawn(move || worker_func(queue_num, channel, cfg, budget))
+                    .expect("failed to spawn nfqueue worker thread")
+            })
+            .collect();
+
+        let mut summary = Summary::default();
+        for worker in workers {
+            match worker.join() {
+                Ok(worker_summary) => summary.add(&worker_summary),
+                Err(_) => log::error!("nfqueue worker terminated unexpectedly"),
+            }
+        }
+        let summary = format!(
+            "{}, {} without verdict, {} socket buffer overruns",
+            summary,
+            default_verdict_count(),
+            enobufs_count()
+        );
+        log::info!("summary: {}", summary);
+        println!("{}", summary);
     }
 }
@@ -0,0 +1,290 @@
+use super::*;
+use std::sync::{Arc, Mutex};
+
+/// Sends verdicts for the packets of one queue via libnetfilter_queue
+pub struct VerdictHandle {
+    qqh: NfqueueQueueHandle,
+}
+
+unsafe impl Send for VerdictHandle {}
+
+impl VerdictHandle {
+    /// Handle without queue, its verdicts are discarded
+    pub fn detached() -> Self {
+        VerdictHandle {
+            qqh: std::ptr::null(),
+        }
+    }
+
+    pub fn set_verdict(&self, id: u32, verdict: u32) {
+        if self.qqh.is_null() {
+            return;
+        }
+        unsafe { nfq_set_verdict2(self.qqh, id, verdict, 0, 0, std::ptr::null_mut()) }
+    }
+
+    pub fn set_verdict_batch(&self, max_id: u32, verdict: u32) {
+        if self.qqh.is_null() {
+            return;
+        }
+        unsafe { nfq_set_verdict_batch2(self.qqh, max_id, verdict, 0) }
+    }
+}
+
+pub struct NfQueueWrapper<T> {
+    qh: NfqueueHandle,
+    cb: Callback<T>,
+    data: T,
+    queue: Arc<Mutex<QueueState>>,
+}
+
+impl<T: Send> NfQueueWrapper<T> {
+    pub fn new(data: T, cb: Callback<T>) -> Self {
+        let qh = unsafe { nfq_open() };
+
+        if qh.is_null() {
+            panic!("Error in nfq_open");
+        }
+
+        NfQueueWrapper {
+            qh,
+            cb,
+            data,
+            queue: Arc::new(Mutex::new(QueueState::new(
+                VerdictHandle {
+                    qqh: std::ptr::null_mut(),
+                },
+                Verdict::Accept,
+            ))),
+        }
+    }
+
+    pub fn open(&mut self, queue_num: u16, options: &QueueOptions) {
+        log::info!("open nfqueue wrapper, queue number: {}", queue_num);
+
+        unsafe { nfq_unbind_pf(self.qh, libc::AF_INET) };
+        unsafe { nfq_bind_pf(self.qh, libc::AF_INET) };
+
+        let self_ptr = self as *mut Self as *mut libc::c_void;
+        let qqh = unsafe { nfq_create_queue(self.qh, queue_num, nfq_callback::<T>, self_ptr) };
+
+        if qqh.is_null() {
+            panic!("Error in nfq_create_queue for queue {}, wrong queue number or insufficient privileges", queue_num);
+        }
+
+        self.queue = Arc::new(Mutex::new(QueueState::new(
+            VerdictHandle { qqh },
+            options.default_verdict,
+        )));
+        unsafe { nfq_set_mode(qqh, NFQNL_COPY_PACKET, options.copy_range) };
+
+        if unsafe { nfq_set_queue_maxlen(qqh, options.max_len) } < 0 {
+            log::error!("failed to set queue max. length to {}", options.max_len);
+        }
+
+        for (flag, name) in options.flags() {
+            if unsafe { nfq_set_queue_flags(qqh, flag, flag) } < 0 {
+                log::error!("failed to enable {}, not supported by kernel", name);
+            }
+        }
+
+        if options.no_enobufs {
+            set_no_enobufs(unsafe { nfq_fd(self.qh) });
+        }
+    }
+
+    /// Receives packets until `stop` is set
+    pub fn run_loop(&mut self, stop: &AtomicBool) {
+        let fd = unsafe { nfq_fd(self.qh) };
+        let mut buf: [u8; 1024 * 1024] = [0; 1024 * 1024];
+        let buf_ptr = buf.as_mut_ptr() as *mut libc::c_void;
+        let buf_len = buf.len() as libc::size_t;
+
+        set_recv_timeout(fd, RECV_TIMEOUT);
+        while !stop.load(Ordering::SeqCst) {
+            let rc = unsafe { libc::recv(fd, buf_ptr, buf_len, 0) };
+            if rc < 0 {
+                handle_recv_error(std::io::Error::last_os_error());
+                continue;
+            }
+
+            unsafe { nfq_handle_packet(self.qh, buf_ptr, rc as libc::c_int) };
+        }
+    }
+
+    /// Unbinds the queue, the kernel drops packets still waiting for a verdict
+    pub fn close(self) {
+        // verdicts of packets outliving the queue are discarded from now on
+        let handle = std::mem::replace(
+            &mut self.queue.lock().unwrap().handle,
+            VerdictHandle::detached(),
+        );
+        let qqh = handle.qqh;
+        if !qqh.is_null() && unsafe { nfq_destroy_queue(qqh) } < 0 {
+            log::error!("failed to destroy queue");
+        }
+        if unsafe { nfq_close(self.qh) } < 0 {
+            log::error!("failed to close nfqueue handle");
+        }
+    }
+}
+
+// C stuff
+type NfqueueHandle = *const libc::c_void;
+type NfqueueQueueHandle = *const libc::c_void;
+type NfqueueCCallback = extern "C" fn(
+    *const libc::c_void,
+    *const libc::c_void,
+    *const libc::c_void,
+    *const libc::c_void,
+);
+type NfqueueData = *const libc::c_void;
+
+/// Metaheader wrapping a packet
+#[repr(C)]
+pub struct NfMsgPacketHdr {
+    /// unique ID of the packet
+    pub packet_id: u32,
+    /// hw protocol (network order)
+    pub hw_protocol: u16,
+    /// Netfilter hook
+    pub hook: u8,
+}
+
+#[link(name = "netfilter_queue")]
+extern "C" {
+    // library setup
+    fn nfq_open() -> NfqueueHandle;
+    fn nfq_close(qh: NfqueueHandle) -> libc::c_int;
+    fn nfq_bind_pf(qh: NfqueueHandle, pf: libc::c_int) -> libc::c_int;
+    fn nfq_unbind_pf(qh: NfqueueHandle, pf: libc::c_int) -> libc::c_int;
+
+    // queue handling
+    fn nfq_fd(h: NfqueueHandle) -> libc::c_int;
+    fn nfq_create_queue(
+        qh: NfqueueHandle,
+        num: u16,
+        cb: NfqueueCCallback,
+        data: *mut libc::c_void,
+    ) -> NfqueueQueueHandle;
+    fn nfq_destroy_queue(qh: NfqueueQueueHandle) -> libc::c_int;
+    fn nfq_handle_packet(qh: NfqueueHandle, buf: *mut libc::c_void, rc: libc::c_int)
+        -> libc::c_int;
+    fn nfq_set_mode(gh: NfqueueQueueHandle, mode: u8, range: u32) -> libc::c_int;
+    fn nfq_set_queue_maxlen(gh: NfqueueQueueHandle, queuelen: u32) -> libc::c_int;
+    fn nfq_set_queue_flags(gh: NfqueueQueueHandle, mask: u32, flags: u32) -> libc::c_int;
+
+    fn nfq_set_verdict2(
+        qqh: *const libc::c_void,
+        id: u32,
+        verdict: u32,
+        mark: u32,
+        data_len: u32,
+        data: *const libc::c_uchar,
+    );
+    fn nfq_set_verdict_batch2(qqh: *const libc::c_void, id: u32, verdict: u32, mark: u32);
+
+    // message parsing functions
+    fn nfq_get_msg_packet_hdr(nfad: NfqueueData) -> *const libc::c_void;
+    fn nfq_get_payload(nfad: NfqueueData, data: *mut *mut libc::c_uchar) -> libc::c_int;
+    fn nfq_get_indev(nfad: NfqueueData) -> u32;
+    fn nfq_get_outdev(nfad: NfqueueData) -> u32;
+    fn nfq_get_skbinfo(nfad: NfqueueData) -> u32;
+    fn nfq_get_timestamp(nfad: NfqueueData, tv: *mut libc::timeval) -> libc::c_int;
+    fn nfq_get_uid(nfad: NfqueueData, uid: *mut u32) -> libc::c_int;
+    fn nfq_get_gid(nfad: NfqueueData, gid: *mut u32) -> libc::c_int;
+}
+
+// original length of a packet truncated to the copy range, libnetfilter_queue has no getter for
+// NFQA_CAP_LEN, so it is taken from the ip header, 0 for unknown protocols
+fn ip_packet_len(payload: &[u8]) -> usize {
+    match payload.first().map(|version| version >> 4) {
+        Some(4) if payload.len() >= 4 => u16::from_be_bytes([payload[2], payload[3]]) as usize,
+        Some(6) if payload.len() >= 6 => 40 + u16::from_be_bytes([payload[4], payload[5]]) as usize,
+        _ => 0,
+    }
+}
+
+#[doc(hidden)]
+extern "C" fn nfq_callback<T>(
+    _qqh: *const libc::c_void,
+    _nfmsg: *const libc::c_void,
+    nfad: *const libc::c_void,
+    data: *const libc::c_void,
+) {
+    let raw: *mut NfQueueWrapper<T> = data as *mut NfQueueWrapper<T>;
+    let q = &mut unsafe { &mut *raw };
+
+    let msg_hdr = unsafe { nfq_get_msg_packet_hdr(nfad) as *const NfMsgPacketHdr };
+
+    let mut c_ptr = std::ptr::null_mut();
+    let payload_len = unsafe { nfq_get_payload(nfad, &mut c_ptr) };
+    let payload: &[u8] = if c_ptr.is_null() || payload_len <= 0 {
+        &[]
+    } else {
+        unsafe { std::slice::from_raw_parts(c_ptr as *const u8, payload_len as usize) }
+    };
+
+    let id = u32::from_be(unsafe { (*msg_hdr).packet_id });
+    q.queue.lock().unwrap().pending.insert(id);
+
+    let mut tv = libc::timeval {
+        tv_sec: 0,
+        tv_usec: 0,
+    };
+    let timestamp = if unsafe { nfq_get_timestamp(nfad, &mut tv) } == 0 {
+        Some(
+            std::time::SystemTime::UNIX_EPOCH
+                + std::time::Duration::new(tv.tv_sec as u64, tv.tv_usec as u32 * 1000),
+        )
+    } else {
+        None
+    };
+
+    let mut uid = 0;
+    let mut gid = 0;
+    let has_uid = unsafe { nfq_get_uid(nfad, &mut uid) } > 0;
+    let has_gid = unsafe { nfq_get_gid(nfad, &mut gid) } > 0;
+
+    let msg = NfqPacket {
+        id,
+        hook: unsafe { (*msg_hdr).hook },
+        indev: unsafe { nfq_get_indev(nfad) },
+        outdev: unsafe { nfq_get_outdev(nfad) },
+        gso: unsafe { nfq_get_skbinfo(nfad) } & NFQA_SKB_GSO != 0,
+        segments: 1,
+        wire_len: ip_packet_len(payload).max(payload.len()),
+        arrival: arrival_time(timestamp),
+        uid: if has_uid { Some(uid) } else { None },
+        gid: if has_gid { Some(gid) } else { None },
+        // libnetfilter_queue has no getter for the conntrack attributes
+        conntrack: None,
+        budget: None,
+        decisions: Vec::new(),
+        verdict_sent: false,
+        queue: Arc::clone(&q.queue),
+        payload: payload.to_vec(),
+    };
+
+    let callback = q.cb;
+    callback(msg, &mut q.data);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packet_length_from_ip_header() {
+        let mut ipv4 = vec![0x45, 0, 0x05, 0xdc];
+        ipv4.resize(128, 0);
+        assert_eq!(ip_packet_len(&ipv4), 1500);
+
+        let mut ipv6 = vec![0x60, 0, 0, 0, 0x05, 0xb4];
+        ipv6.resize(128, 0);
+        assert_eq!(ip_packet_len(&ipv6), 1500);
+
+        assert_eq!(ip_packet_len(&[0x45, 0]), 0);
+        assert_eq!(ip_packet_len(&[]), 0);
+    }
+}
@@ -0,0 +1,423 @@
+#[cfg(not(feature = "netlink"))]
+mod libnfq;
+#[cfg(feature = "netlink")]
+mod netlink;
+
+#[cfg(not(feature = "netlink"))]
+pub use libnfq::NfQueueWrapper;
+#[cfg(not(feature = "netlink"))]
+use libnfq::VerdictHandle;
+#[cfg(feature = "netlink")]
+pub use netlink::NfQueueWrapper;
+#[cfg(feature = "netlink")]
+use netlink::VerdictHandle;
+
+use crate::capture;
+use crate::packet_budget::BudgetGuard;
+use crate::queuing_model::Decision;
+use crate::trace;
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Drop,
+    Accept,
+}
+
+impl Verdict {
+    fn to_c_verdict(self) -> u32 {
+        match self {
+            Verdict::Accept => 1,
+            Verdict::Drop => 0,
+        }
+    }
+}
+
+/// Queue handle shared between the receiving thread and the packets waiting for a verdict
+pub struct QueueState {
+    handle: VerdictHandle,
+    /// ids of all packets waiting for a verdict
+    pending: BTreeSet<u32>,
+    /// verdict of packets dropped without one
+    default_verdict: Verdict,
+}
+
+impl QueueState {
+    fn new(handle: VerdictHandle, default_verdict: Verdict) -> Self {
+        QueueState {
+            handle,
+            pending: BTreeSet::new(),
+            default_verdict,
+        }
+    }
+}
+
+/// number of packets which got the default verdict, because they were dropped without one
+static DEFAULT_VERDICT_COUNT: AtomicU64 = AtomicU64::new(0);
+
+pub fn default_verdict_count() -> u64 {
+    DEFAULT_VERDICT_COUNT.load(Ordering::Relaxed)
+}
+
+// only the netlink backend delivers connection tracking info
+#[cfg_attr(not(feature = "netlink"), allow(dead_code))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConntrackState {
+    New,
+    Established,
+    Related,
+    Untracked,
+}
+
+/// Connection tracking entry of a packet
+#[derive(Clone, Copy, Debug)]
+pub struct Conntrack {
+    pub id: u32,
+    pub state: ConntrackState,
+    /// packet is sent in reply direction of the connection
+    pub reply: bool,
+}
+
+impl std::fmt::Display for Conntrack {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "id {}, state {:?}{}",
+            self.id,
+            self.state,
+            if self.reply { " (reply)" } else { "" }
+        )
+    }
+}
+
+pub struct NfqPacket {
+    pub id: u32,
+    pub payload: Vec<u8>,
+    /// netfilter hook the packet was queued from (NF_INET_*)
+    pub hook: u8,
+    /// input interface index, 0 if unknown
+    pub indev: u32,
+    /// output interface index, 0 if unknown
+    pub outdev: u32,
+    /// generic segmentation offload packet, consisting of several segments on the wire
+    pub gso: bool,
+    /// number of packets on the wire, more than one for gso packets
+    pub segments: u32,
+    /// size of all segments on the wire in bytes, the payload may be only a part of it
+    pub wire_len: usize,
+    /// time the kernel received the packet, or the packet was queued if the kernel has no timestamp
+    pub arrival: Instant,
+    /// user and group id of the sending socket, if known
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// connection tracking entry, if enabled
+    pub conntrack: Option<Conntrack>,
+    /// share of the global packet budget while the packet is queued
+    pub budget: Option<BudgetGuard>,
+    /// what the models did with the packet, in chain order
+    pub decisions: Vec<Decision>,
+    queue: Arc<Mutex<QueueState>>,
+    verdict_sent: bool,
+}
+
+impl NfqPacket {
+    pub fn get_payload(&self) -> &[u8] {
+        self.payload.as_slice()
+    }
+
+    pub fn set_verdict(mut self, verdict: Verdict) {
+        let c_verdict = verdict.to_c_verdict();
+        log::debug!("set verdict {}, {}", self.id, c_verdict);
+        record(&self, verdict);
+        let mut queue = self.queue.lock().unwrap();
+        queue.pending.remove(&self.id);
+        queue.handle.set_verdict(self.id, c_verdict);
+        self.verdict_sent = true;
+    }
+}
+
+#[cfg(test)]
+impl NfqPacket {
+    /// Packet of `segments` mtu sized segments without queue, for tests of the models
+    pub fn detached(id: u32, segments: u32) -> NfqPacket {
+        NfqPacket {
+            id,
+            payload: Vec::new(),
+            hook: 0,
+            indev: 0,
+            outdev: 0,
+            gso: segments > 1,
+            segments,
+            wire_len: segments as usize * 1500,
+            arrival: Instant::now(),
+            uid: None,
+            gid: None,
+            conntrack: None,
+            budget: None,
+            decisions: Vec::new(),
+            queue: Arc::new(Mutex::new(QueueState::new(
+                VerdictHandle::detached(),
+                Verdict::Accept,
+            ))),
+            verdict_sent: false,
+        }
+    }
+}
+
+// the kernel keeps a packet without verdict forever, so a packet dropped without
+// one (on a panic, a replaced model or a bug) gets the default verdict
+impl Drop for NfqPacket {
+    fn drop(&mut self) {
+        if self.verdict_sent {
+            return;
+        }
+        // don't panic again while unwinding
+        let mut queue = match self.queue.lock() {
+            Ok(queue) => queue,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let verdict = queue.default_verdict;
+        queue.pending.remove(&self.id);
+        queue.handle.set_verdict(self.id, verdict.to_c_verdict());
+        drop(queue);
+        record(self, verdict);
+
+        let count = DEFAULT_VERDICT_COUNT.fetch_add(1, Ordering::Relaxed) + 1;
+        if count.is_power_of_two() {
+            log::warn!(
+                "packet {} dropped without verdict, set default verdict {:?} ({} packets so far)",
+                self.id,
+                verdict,
+                count
+            );
+        } else {
+            log::debug!(
+                "packet {} dropped without verdict, set default verdict {:?}",
+                self.id,
+                verdict
+            );
+        }
+    }
+}
+
+// writes the packet to the capture and trace files, if enabled
+fn record(packet: &NfqPacket, verdict: Verdict) {
+    capture::record(packet, verdict);
+    trace::record(packet, verdict);
+}
+
+/// Sets the same verdict for all packets (of one queue) with as few netlink messages as possible.
+/// A batch verdict applies to all waiting packets up to an id, so it is used for the longest
+/// run of the oldest waiting packets, the remaining packets get a verdict one by one.
+pub fn set_verdict_batch(mut packets: Vec<NfqPacket>, verdict: Verdict) {
+    let queue = match packets.first() {
+        Some(packet) => Arc::clone(&packet.queue),
+        None => return,
+    };
+    debug_assert!(packets.iter().all(|p| Arc::ptr_eq(&p.queue, &queue)));
+
+    let c_verdict = verdict.to_c_verdict();
+    let mut ids: Vec<u32> = packets.iter().map(|p| p.id).collect();
+    ids.sort_unstable();
+
+    let mut queue = queue.lock().unwrap();
+    let batch_len = batch_prefix_len(&queue.pending, &ids);
+
+    if batch_len > 1 {
+        let max_id = ids[batch_len - 1];
+        log::debug!("set batch verdict up to {}, {}", max_id, c_verdict);
+        queue.handle.set_verdict_batch(max_id, c_verdict);
+        queue.pending = match max_id.checked_add(1) {
+            Some(next_id) => queue.pending.split_off(&next_id),
+            None => BTreeSet::new(),
+        };
+    }
+
+    let single_start = if batch_len > 1 { batch_len } else { 0 };
+    for id in &ids[single_start..] {
+        log::debug!("set verdict {}, {}", id, c_verdict);
+        queue.pending.remove(id);
+        queue.handle.set_verdict(*id, c_verdict);
+    }
+    drop(queue);
+
+    for packet in packets.iter_mut() {
+        record(packet, verdict);
+        packet.verdict_sent = true;
+    }
+}
+
+// number of the oldest waiting packets which are all contained in the sorted ids
+fn batch_prefix_len(pending: &BTreeSet<u32>, sorted_ids: &[u32]) -> usize {
+    // packet ids wrap around, the kernel compares them with serial number arithmetic
+    if let (Some(first), Some(last)) = (pending.iter().next(), pending.iter().next_back()) {
+        if last - first > u32::MAX / 2 {
+            return 0;
+        }
+    }
+
+    pending
+        .iter()
+        .zip(sorted_ids.iter())
+        .take_while(|(pending_id, id)| pending_id == id)
+        .count()
+}
+
+pub type Callback<T> = fn(NfqPacket, &mut T) -> ();
+
+/// Kernel queue settings applied on open
+pub struct QueueOptions {
+    /// max. number of packets the kernel keeps waiting for a verdict
+    pub max_len: u32,
+    /// number of bytes copied to userspace from the start of each packet
+    pub copy_range: u32,
+    /// verdict for packets dropped by the degrader without a verdict
+    pub default_verdict: Verdict,
+    /// accept packets instead of dropping them if the kernel queue is full
+    pub fail_open: bool,
+    /// don't report socket buffer overruns (ENOBUFS) to the degrader
+    pub no_enobufs: bool,
+    /// queue gso packets unsegmented (NFQA_CFG_F_GSO)
+    pub gso: bool,
+    /// deliver user and group id of the sending socket (NFQA_CFG_F_UID_GID)
+    pub uid_gid: bool,
+    /// deliver connection tracking id and state (NFQA_CFG_F_CONNTRACK)
+    pub conntrack: bool,
+}
+
+impl Default for QueueOptions {
+    fn default() -> Self {
+        QueueOptions {
+            max_len: 1024 * 1024 * 1024,
+            copy_range: 0xfffff,
+            default_verdict: Verdict::Accept,
+            fail_open: false,
+            no_enobufs: false,
+            gso: false,
+            uid_gid: false,
+            conntrack: false,
+        }
+    }
+}
+
+impl QueueOptions {
+    // queue flags to enable, each is set separately as older kernels don't support all of them
+    fn flags(&self) -> Vec<(u32, &'static str)> {
+        [
+            (self.fail_open, NFQA_CFG_F_FAIL_OPEN, "fail open"),
+            (self.gso, NFQA_CFG_F_GSO, "gso"),
+            (self.uid_gid, NFQA_CFG_F_UID_GID, "uid/gid"),
+            (self.conntrack, NFQA_CFG_F_CONNTRACK, "conntrack"),
+        ]
+        .iter()
+        .filter(|(enabled, _, _)| *enabled)
+        .map(|(_, flag, name)| (*flag, *name))
+        .collect()
+    }
+}
+
+// shared by the backends, both read from a netlink socket
+fn set_no_enobufs(fd: libc::c_int) {
+    let enable: libc::c_int = 1;
+    let rc = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_NETLINK,
+            libc::NETLINK_NO_ENOBUFS,
+            &enable as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if rc < 0 {
+        log::error!(
+            "failed to set NETLINK_NO_ENOBUFS: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+// the receive loop wakes up regularly to check if it should stop
+const RECV_TIMEOUT: std::time::Duration = std::time::Duration::from_millis(100);
+
+fn set_recv_timeout(fd: libc::c_int, timeout: std::time::Duration) {
+    let tv = libc::timeval {
+        tv_sec: timeout.as_secs() as libc::time_t,
+        tv_usec: timeout.subsec_micros() as libc::suseconds_t,
+    };
+    let rc = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_RCVTIMEO,
+            &tv as *const libc::timeval as *const libc::c_void,
+            std::mem::size_of::<libc::timeval>() as libc::socklen_t,
+        )
+    };
+    if rc < 0 {
+        log::error!(
+            "failed to set receive timeout: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+/// number of socket buffer overruns (ENOBUFS) of all queues, each losing packets
+static ENOBUFS_COUNT: AtomicU64 = AtomicU64::new(0);
+
+pub fn enobufs_count() -> u64 {
+    ENOBUFS_COUNT.load(Ordering::Relaxed)
+}
+
+fn handle_recv_error(error: std::io::Error) {
+    match error.raw_os_error() {
+        Some(libc::ENOBUFS) => {
+            let count = ENOBUFS_COUNT.fetch_add(1, Ordering::Relaxed) + 1;
+            log::warn!(
+                "socket buffer overrun (ENOBUFS), packets lost, occurrences: {}",
+                count
+            );
+        }
+        Some(libc::EINTR) | Some(libc::EAGAIN) => {}
+        _ => log::error!("error receiving from nfqueue socket: {}", error),
+    }
+}
+
+// converts the kernel receive timestamp into the monotonic clock of the degrader
+fn arrival_time(timestamp: Option<SystemTime>) -> Instant {
+    let now = Instant::now();
+    timestamp
+        .and_then(|timestamp| SystemTime::now().duration_since(timestamp).ok())
+        .and_then(|age| now.checked_sub(age))
+        .unwrap_or(now)
+}
+
+const NFQNL_COPY_PACKET: u8 = 0x02;
+const NFQA_CFG_F_FAIL_OPEN: u32 = 0x01;
+const NFQA_CFG_F_CONNTRACK: u32 = 0x02;
+const NFQA_CFG_F_GSO: u32 = 0x04;
+const NFQA_CFG_F_UID_GID: u32 = 0x08;
+const NFQA_SKB_GSO: u32 = 0x02;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batch_covers_oldest_waiting_packets() {
+        let pending: BTreeSet<u32> = (1..=10).collect();
+        assert_eq!(batch_prefix_len(&pending, &[1, 2, 3, 7]), 3);
+        assert_eq!(batch_prefix_len(&pending, &[2, 3, 4]), 0);
+        assert_eq!(batch_prefix_len(&pending, &[]), 0);
+        let all: Vec<u32> = pending.iter().copied().collect();
+        assert_eq!(batch_prefix_len(&pending, &all), 10);
+    }
+
+    #[test]
+    fn no_batch_on_id_wrap_around() {
+        let pending: BTreeSet<u32> = [1, 2, u32::MAX - 1, u32::MAX].iter().copied().collect();
+        assert_eq!(batch_prefix_len(&pending, &[1, 2]), 0);
+    }
+}
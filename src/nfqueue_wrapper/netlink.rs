@@ -0,0 +1,698 @@
+//! nfnetlink_queue protocol spoken directly over an AF_NETLINK socket,
+//! see linux/netfilter/nfnetlink_queue.h for the message layout.
+use super::*;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+const NETLINK_NETFILTER: libc::c_int = 12;
+
+// netlink message header: length, type, flags, sequence number, port id
+const NLMSG_HDR_LEN: usize = 16;
+const NLMSG_ERROR: u16 = 2;
+const NLM_F_REQUEST: u16 = 0x01;
+const NLM_F_ACK: u16 = 0x04;
+
+// nfnetlink header: family, version, resource id (queue number)
+const NFGENMSG_LEN: usize = 4;
+const NFNETLINK_V0: u8 = 0;
+const NFNL_SUBSYS_QUEUE: u16 = 3;
+
+const NFQNL_MSG_PACKET: u16 = 0;
+const NFQNL_MSG_VERDICT: u16 = 1;
+const NFQNL_MSG_CONFIG: u16 = 2;
+const NFQNL_MSG_VERDICT_BATCH: u16 = 3;
+
+const NFQNL_CFG_CMD_BIND: u8 = 1;
+const NFQNL_CFG_CMD_UNBIND: u8 = 2;
+const NFQNL_CFG_CMD_PF_BIND: u8 = 3;
+const NFQNL_CFG_CMD_PF_UNBIND: u8 = 4;
+
+const NFQA_CFG_CMD: u16 = 1;
+const NFQA_CFG_PARAMS: u16 = 2;
+const NFQA_CFG_QUEUE_MAXLEN: u16 = 3;
+const NFQA_CFG_MASK: u16 = 4;
+const NFQA_CFG_FLAGS: u16 = 5;
+
+const NFQA_PACKET_HDR: u16 = 1;
+const NFQA_VERDICT_HDR: u16 = 2;
+const NFQA_TIMESTAMP: u16 = 4;
+const NFQA_IFINDEX_INDEV: u16 = 5;
+const NFQA_IFINDEX_OUTDEV: u16 = 6;
+const NFQA_PAYLOAD: u16 = 10;
+const NFQA_CT: u16 = 11;
+const NFQA_CT_INFO: u16 = 12;
+const NFQA_CAP_LEN: u16 = 13;
+const NFQA_SKB_INFO: u16 = 14;
+const NFQA_UID: u16 = 16;
+const NFQA_GID: u16 = 17;
+
+const CTA_ID: u16 = 12;
+
+// upper attribute type bits are flags (nested, network byte order)
+const NLA_TYPE_MASK: u16 = 0x3fff;
+
+/// Sends verdicts for the packets of one queue over the netlink socket
+pub struct VerdictHandle {
+    fd: libc::c_int,
+    queue_num: u16,
+}
+
+impl VerdictHandle {
+    /// Handle without queue, its verdicts are discarded
+    pub fn detached() -> Self {
+        VerdictHandle {
+            fd: -1,
+            queue_num: 0,
+        }
+    }
+
+    pub fn set_verdict(&self, id: u32, verdict: u32) {
+        self.send_verdict(NFQNL_MSG_VERDICT, id, verdict);
+    }
+
+    pub fn set_verdict_batch(&self, max_id: u32, verdict: u32) {
+        self.send_verdict(NFQNL_MSG_VERDICT_BATCH, max_id, verdict);
+    }
+
+    fn send_verdict(&self, msg_type: u16, id: u32, verdict: u32) {
+        if self.fd < 0 {
+            return;
+        }
+        let mut verdict_hdr = [0u8; 8];
+        verdict_hdr[..4].copy_from_slice(&verdict.to_be_bytes());
+        verdict_hdr[4..].copy_from_slice(&id.to_be_bytes());
+
+        let msg = Message::new(msg_type, 0, 0, libc::AF_UNSPEC as u8, self.queue_num)
+            .attr(NFQA_VERDICT_HDR, &verdict_hdr)
+            .finish();
+        if let Err(e) = send(self.fd, &msg) {
+            log::error!("failed to send verdict for packet {}: {}", id, e);
+        }
+    }
+}
+
+pub struct NfQueueWrapper<T> {
+    fd: libc::c_int,
+    seq: u32,
+    queue_num: u16,
+    cb: Callback<T>,
+    data: T,
+    buf: Vec<u8>,
+    queue: Arc<Mutex<QueueState>>,
+}
+
+impl<T: Send> NfQueueWrapper<T> {
+    pub fn new(data: T, cb: Callback<T>) -> Self {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                NETLINK_NETFILTER,
+            )
+        };
+        if fd < 0 {
+            panic!(
+                "Error opening netlink socket: {}",
+                io::Error::last_os_error()
+            );
+        }
+
+        let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        let rc = unsafe {
+            libc::bind(
+                fd,
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if rc < 0 {
+            panic!(
+                "Error binding netlink socket: {}",
+                io::Error::last_os_error()
+            );
+        }
+
+        NfQueueWrapper {
+            fd,
+            seq: 0,
+            queue_num: 0,
+            cb,
+            data,
+            buf: vec![0; 1024 * 1024],
+            queue: Arc::new(Mutex::new(QueueState::new(
+                VerdictHandle { fd, queue_num: 0 },
+                Verdict::Accept,
+            ))),
+        }
+    }
+
+    pub fn open(&mut self, queue_num: u16, options: &QueueOptions) {
+        log::info!("open nfqueue netlink socket, queue number: {}", queue_num);
+
+        self.queue_num = queue_num;
+        self.queue = Arc::new(Mutex::new(QueueState::new(
+            VerdictHandle {
+                fd: self.fd,
+                queue_num,
+            },
+            options.default_verdict,
+        )));
+
+        // obsolete since linux 3.8, but still required by older kernels
+        let _ = self.config_cmd(NFQNL_CFG_CMD_PF_UNBIND, 0, libc::AF_INET as u16);
+        let _ = self.config_cmd(NFQNL_CFG_CMD_PF_BIND, 0, libc::AF_INET as u16);
+
+        if let Err(e) = self.config_cmd(NFQNL_CFG_CMD_BIND, queue_num, 0) {
+            panic!(
+                "Error binding queue {}, wrong queue number or insufficient privileges: {}",
+                queue_num, e
+            );
+        }
+
+        let mut params = [0u8; 5];
+        params[..4].copy_from_slice(&options.copy_range.to_be_bytes());
+        params[4] = NFQNL_COPY_PACKET;
+        if let Err(e) = self.config(NFQA_CFG_PARAMS, &params) {
+            log::error!("failed to set copy mode: {}", e);
+        }
+
+        if let Err(e) = self.config(NFQA_CFG_QUEUE_MAXLEN, &options.max_len.to_be_bytes()) {
+            log::error!(
+                "failed to set queue max. length to {}: {}",
+                options.max_len,
+                e
+            );
+        }
+
+        for (flag, name) in options.flags() {
+            if let Err(e) = self.config_flags(flag, flag) {
+                log::error!("failed to enable {}, not supported by kernel: {}", name, e);
+            }
+        }
+
+        if options.no_enobufs {
+            set_no_enobufs(self.fd);
+        }
+    }
+
+    /// Receives packets until `stop` is set
+    pub fn run_loop(&mut self, stop: &AtomicBool) {
+        set_recv_timeout(self.fd, RECV_TIMEOUT);
+        while !stop.load(Ordering::SeqCst) {
+            if let Err(e) = self.receive(None) {
+                handle_recv_error(e);
+            }
+        }
+    }
+
+    /// Unbinds the queue, the kernel drops packets still waiting for a verdict
+    pub fn close(mut self) {
+        // verdicts of packets outliving the queue are discarded from now on
+        self.queue.lock().unwrap().handle = VerdictHandle::detached();
+        if let Err(e) = self.config_cmd(NFQNL_CFG_CMD_UNBIND, self.queue_num, 0) {
+            log::error!("failed to unbind queue {}: {}", self.queue_num, e);
+        }
+        unsafe { libc::close(self.fd) };
+    }
+
+    fn config_cmd(&mut self, command: u8, queue_num: u16, pf: u16) -> io::Result<()> {
+        let mut cmd = [0u8; 4];
+        cmd[0] = command;
+        cmd[2..].copy_from_slice(&pf.to_be_bytes());
+        let msg = Message::new(NFQNL_MSG_CONFIG, NLM_F_ACK, self.next_seq(), 0, queue_num)
+            .attr(NFQA_CFG_CMD, &cmd)
+            .finish();
+        self.request(&msg)
+    }
+
+    fn config(&mut self, attr_type: u16, data: &[u8]) -> io::Result<()> {
+        let msg = Message::new(
+            NFQNL_MSG_CONFIG,
+            NLM_F_ACK,
+            self.next_seq(),
+            0,
+            self.queue_num,
+        )
+        .attr(attr_type, data)
+        .finish();
+        self.request(&msg)
+    }
+
+    fn config_flags(&mut self, mask: u32, flags: u32) -> io::Result<()> {
+        let msg = Message::new(
+            NFQNL_MSG_CONFIG,
+            NLM_F_ACK,
+            self.next_seq(),
+            0,
+            self.queue_num,
+        )
+        .attr(NFQA_CFG_MASK, &mask.to_be_bytes())
+        .attr(NFQA_CFG_FLAGS, &flags.to_be_bytes())
+        .finish();
+        self.request(&msg)
+    }
+
+    fn next_seq(&mut self) -> u32 {
+        self.seq = self.seq.wrapping_add(1);
+        self.seq
+    }
+
+    // sends a config message and waits for its acknowledgement, packets
+    // arriving in the meantime are passed to the callback
+    fn request(&mut self, msg: &[u8]) -> io::Result<()> {
+        send(self.fd, msg)?;
+        let seq = self.seq;
+        loop {
+            if let Some(result) = self.receive(Some(seq))? {
+                return result;
+            }
+        }
+    }
+
+    // receives one datagram and dispatches the contained messages, returns the
+    // acknowledgement result of the awaited sequence number if it was received
+    fn receive(&mut self, awaited_seq: Option<u32>) -> io::Result<Option<io::Result<()>>> {
+        let rc = unsafe {
+            libc::recv(
+                self.fd,
+                self.buf.as_mut_ptr() as *mut libc::c_void,
+                self.buf.len(),
+                0,
+            )
+        };
+        if rc < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut ack = None;
+        let mut packets = Vec::new();
+        for msg in messages(&self.buf[..rc as usize]) {
+            if msg.msg_type == NLMSG_ERROR {
+                let result = parse_error(msg.payload);
+                if awaited_seq == Some(msg.seq) {
+                    ack = Some(result);
+                } else if let Err(e) = result {
+                    log::error!("netlink error: {}", e);
+                }
+            } else if msg.msg_type == (NFNL_SUBSYS_QUEUE << 8) | NFQNL_MSG_PACKET {
+                match parse_packet(msg.payload) {
+                    Some(packet) => packets.push(packet),
+                    None => log::error!("malformed nfqueue packet message"),
+                }
+            }
+        }
+
+        for attrs in packets {
+            self.queue.lock().unwrap().pending.insert(attrs.id);
+            let packet = NfqPacket {
+                id: attrs.id,
+                hook: attrs.hook,
+                indev: attrs.indev,
+                outdev: attrs.outdev,
+                gso: attrs.skb_info & NFQA_SKB_GSO != 0,
+                segments: 1,
+                wire_len: attrs
+                    .cap_len
+                    .map_or(attrs.payload.len(), |len| len as usize),
+                arrival: arrival_time(attrs.timestamp),
+                uid: attrs.uid,
+                gid: attrs.gid,
+                conntrack: attrs.conntrack,
+                budget: None,
+                decisions: Vec::new(),
+                verdict_sent: false,
+                queue: Arc::clone(&self.queue),
+                payload: attrs.payload,
+            };
+            (self.cb)(packet, &mut self.data);
+        }
+
+        Ok(ack)
+    }
+}
+
+fn send(fd: libc::c_int, msg: &[u8]) -> io::Result<()> {
+    let rc = unsafe { libc::send(fd, msg.as_ptr() as *const libc::c_void, msg.len(), 0) };
+    if rc < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+// builds one nfnetlink queue message
+struct Message {
+    buf: Vec<u8>,
+}
+
+impl Message {
+    fn new(msg_type: u16, flags: u16, seq: u32, family: u8, queue_num: u16) -> Self {
+        let mut buf = Vec::with_capacity(64);
+        buf.extend_from_slice(&0u32.to_ne_bytes());
+        buf.extend_from_slice(&((NFNL_SUBSYS_QUEUE << 8) | msg_type).to_ne_bytes());
+        buf.extend_from_slice(&(NLM_F_REQUEST | flags).to_ne_bytes());
+        buf.extend_from_slice(&seq.to_ne_bytes());
+        buf.extend_from_slice(&0u32.to_ne_bytes());
+        buf.push(family);
+        buf.push(NFNETLINK_V0);
+        buf.extend_from_slice(&queue_num.to_be_bytes());
+        Message { buf }
+    }
+
+    fn attr(mut self, attr_type: u16, data: &[u8]) -> Self {
+        let len = 4 + data.len();
+        self.buf.extend_from_slice(&(len as u16).to_ne_bytes());
+        self.buf.extend_from_slice(&attr_type.to_ne_bytes());
+        self.buf.extend_from_slice(data);
+        self.buf.resize(align(self.buf.len()), 0);
+        self
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let len = self.buf.len() as u32;
+        self.buf[..4].copy_from_slice(&len.to_ne_bytes());
+        self.buf
+    }
+}
+
+struct NetlinkMessage<'a> {
+    msg_type: u16,
+    seq: u32,
+    payload: &'a [u8],
+}
+
+fn read_u16_ne(buf: &[u8]) -> u16 {
+    u16::from_ne_bytes([buf[0], buf[1]])
+}
+
+fn read_u32_ne(buf: &[u8]) -> u32 {
+    u32::from_ne_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+fn read_u32_be(buf: &[u8]) -> u32 {
+    u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+fn messages(mut buf: &[u8]) -> Vec<NetlinkMessage<'_>> {
+    let mut msgs = Vec::new();
+    while buf.len() >= NLMSG_HDR_LEN {
+        let len = read_u32_ne(buf) as usize;
+        if len < NLMSG_HDR_LEN || len > buf.len() {
+            break;
+        }
+        msgs.push(NetlinkMessage {
+            msg_type: read_u16_ne(&buf[4..]),
+            seq: read_u32_ne(&buf[8..]),
+            payload: &buf[NLMSG_HDR_LEN..len],
+        });
+        buf = &buf[align(len).min(buf.len())..];
+    }
+    msgs
+}
+
+fn attributes(mut buf: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut attrs = Vec::new();
+    while buf.len() >= 4 {
+        let len = read_u16_ne(buf) as usize;
+        if len < 4 || len > buf.len() {
+            break;
+        }
+        attrs.push((read_u16_ne(&buf[2..]) & NLA_TYPE_MASK, &buf[4..len]));
+        buf = &buf[align(len).min(buf.len())..];
+    }
+    attrs
+}
+
+// payload of NLMSG_ERROR: negative errno, 0 is an acknowledgement
+fn parse_error(payload: &[u8]) -> io::Result<()> {
+    if payload.len() < 4 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "truncated netlink error",
+        ));
+    }
+    match -(read_u32_ne(payload) as i32) {
+        0 => Ok(()),
+        errno => Err(io::Error::from_raw_os_error(errno)),
+    }
+}
+
+// NFQA_TIMESTAMP: seconds and microseconds (be64)
+fn parse_timestamp(data: &[u8]) -> Option<SystemTime> {
+    if data.len() < 16 {
+        return None;
+    }
+    let mut sec = [0u8; 8];
+    let mut usec = [0u8; 8];
+    sec.copy_from_slice(&data[..8]);
+    usec.copy_from_slice(&data[8..16]);
+    let since_epoch = Duration::from_secs(u64::from_be_bytes(sec))
+        + Duration::from_micros(u64::from_be_bytes(usec));
+    SystemTime::UNIX_EPOCH.checked_add(since_epoch)
+}
+
+struct PacketAttributes {
+    id: u32,
+    hook: u8,
+    indev: u32,
+    outdev: u32,
+    skb_info: u32,
+    timestamp: Option<SystemTime>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    conntrack: Option<Conntrack>,
+    // original length, if the payload is truncated to the copy range
+    cap_len: Option<u32>,
+    payload: Vec<u8>,
+}
+
+// NFQA_CT (nested conntrack attributes) and NFQA_CT_INFO (enum ip_conntrack_info, be32)
+fn parse_conntrack(ct: Option<&[u8]>, ct_info: Option<&[u8]>) -> Option<Conntrack> {
+    let ct_info = ct_info.filter(|data| data.len() >= 4).map(read_u32_be)?;
+    let (state, reply) = match ct_info {
+        0 => (ConntrackState::Established, false),
+        1 => (ConntrackState::Related, false),
+        2 => (ConntrackState::New, false),
+        3 => (ConntrackState::Established, true),
+        4 => (ConntrackState::Related, true),
+        _ => (ConntrackState::Untracked, false),
+    };
+    let id = ct
+        .map(attributes)
+        .and_then(|attrs| {
+            attrs
+                .iter()
+                .find(|(attr_type, data)| *attr_type == CTA_ID && data.len() >= 4)
+                .map(|(_, data)| read_u32_be(data))
+        })
+        .unwrap_or(0);
+    Some(Conntrack { id, state, reply })
+}
+
+fn parse_packet(payload: &[u8]) -> Option<PacketAttributes> {
+    if payload.len() < NFGENMSG_LEN {
+        return None;
+    }
+
+    let mut packet_hdr = None;
+    let mut packet = PacketAttributes {
+        id: 0,
+        hook: 0,
+        indev: 0,
+        outdev: 0,
+        skb_info: 0,
+        timestamp: None,
+        uid: None,
+        gid: None,
+        conntrack: None,
+        cap_len: None,
+        payload: Vec::new(),
+    };
+    let mut ct = None;
+    let mut ct_info = None;
+
+    for (attr_type, data) in attributes(&payload[NFGENMSG_LEN..]) {
+        match attr_type {
+            // packet id (be32), hw protocol (be16), hook (u8)
+            NFQA_PACKET_HDR if data.len() >= 7 => packet_hdr = Some((read_u32_be(data), data[6])),
+            NFQA_IFINDEX_INDEV if data.len() >= 4 => packet.indev = read_u32_be(data),
+            NFQA_IFINDEX_OUTDEV if data.len() >= 4 => packet.outdev = read_u32_be(data),
+            NFQA_SKB_INFO if data.len() >= 4 => packet.skb_info = read_u32_be(data),
+            NFQA_TIMESTAMP => packet.timestamp = parse_timestamp(data),
+            NFQA_UID if data.len() >= 4 => packet.uid = Some(read_u32_be(data)),
+            NFQA_GID if data.len() >= 4 => packet.gid = Some(read_u32_be(data)),
+            NFQA_CAP_LEN if data.len() >= 4 => packet.cap_len = Some(read_u32_be(data)),
+            NFQA_CT => ct = Some(data),
+            NFQA_CT_INFO => ct_info = Some(data),
+            NFQA_PAYLOAD => packet.payload = data.to_vec(),
+            _ => {}
+        }
+    }
+    packet.conntrack = parse_conntrack(ct, ct_info);
+
+    let (id, hook) = packet_hdr?;
+    packet.id = id;
+    packet.hook = hook;
+    Some(packet)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet_message(id: u32, hook: u8, outdev: u32, payload: &[u8]) -> Vec<u8> {
+        let mut packet_hdr = [0u8; 7];
+        packet_hdr[..4].copy_from_slice(&id.to_be_bytes());
+        packet_hdr[4..6].copy_from_slice(&0x0800u16.to_be_bytes());
+        packet_hdr[6] = hook;
+        Message::new(NFQNL_MSG_PACKET, 0, 0, libc::AF_INET as u8, 0)
+            .attr(NFQA_PACKET_HDR, &packet_hdr)
+            .attr(NFQA_IFINDEX_OUTDEV, &outdev.to_be_bytes())
+            .attr(NFQA_PAYLOAD, payload)
+            .finish()
+    }
+
+    #[test]
+    fn parse_packet_messages() {
+        let mut buf = packet_message(7, 3, 2, &[1, 2, 3, 4, 5]);
+        buf.extend(packet_message(8, 1, 0, &[6; 9]));
+
+        let msgs = messages(&buf);
+        assert_eq!(msgs.len(), 2);
+
+        let first = parse_packet(msgs[0].payload).unwrap();
+        assert_eq!(
+            msgs[0].msg_type,
+            (NFNL_SUBSYS_QUEUE << 8) | NFQNL_MSG_PACKET
+        );
+        assert_eq!(first.id, 7);
+        assert_eq!(first.hook, 3);
+        assert_eq!(first.indev, 0);
+        assert_eq!(first.outdev, 2);
+        assert_eq!(first.payload, vec![1, 2, 3, 4, 5]);
+
+        let second = parse_packet(msgs[1].payload).unwrap();
+        assert_eq!(second.id, 8);
+        assert_eq!(second.payload, vec![6; 9]);
+    }
+
+    #[test]
+    fn parse_packet_metadata() {
+        let mut packet_hdr = [0u8; 7];
+        packet_hdr[..4].copy_from_slice(&1u32.to_be_bytes());
+        let mut timestamp = [0u8; 16];
+        timestamp[..8].copy_from_slice(&1_600_000_000u64.to_be_bytes());
+        timestamp[8..].copy_from_slice(&250_000u64.to_be_bytes());
+        let mut ct_id = Vec::new();
+        ct_id.extend_from_slice(&8u16.to_ne_bytes());
+        ct_id.extend_from_slice(&CTA_ID.to_ne_bytes());
+        ct_id.extend_from_slice(&4711u32.to_be_bytes());
+
+        let msg = Message::new(NFQNL_MSG_PACKET, 0, 0, libc::AF_INET as u8, 0)
+            .attr(NFQA_PACKET_HDR, &packet_hdr)
+            .attr(NFQA_TIMESTAMP, &timestamp)
+            .attr(NFQA_UID, &1000u32.to_be_bytes())
+            .attr(NFQA_CT, &ct_id)
+            .attr(NFQA_CT_INFO, &3u32.to_be_bytes())
+            .attr(NFQA_CAP_LEN, &1500u32.to_be_bytes())
+            .attr(NFQA_PAYLOAD, &[0x45; 128])
+            .finish();
+
+        let packet = parse_packet(messages(&msg)[0].payload).unwrap();
+        assert_eq!(
+            packet.timestamp,
+            Some(SystemTime::UNIX_EPOCH + Duration::from_millis(1_600_000_000_250))
+        );
+        assert_eq!(packet.uid, Some(1000));
+        assert_eq!(packet.gid, None);
+        let conntrack = packet.conntrack.unwrap();
+        assert_eq!(conntrack.id, 4711);
+        assert_eq!(conntrack.state, ConntrackState::Established);
+        assert!(conntrack.reply);
+        assert_eq!(packet.cap_len, Some(1500));
+        assert_eq!(packet.payload.len(), 128);
+    }
+
+    #[test]
+    fn parse_acknowledgement() {
+        let mut ack = Vec::new();
+        ack.extend_from_slice(&0i32.to_ne_bytes());
+        assert!(parse_error(&ack).is_ok());
+
+        let mut error = Vec::new();
+        error.extend_from_slice(&(-libc::EPERM).to_ne_bytes());
+        let e = parse_error(&error).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::EPERM));
+    }
+
+    #[test]
+    fn default_verdict_for_packet_without_verdict() {
+        let mut fds = [0; 2];
+        let rc = unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_DGRAM, 0, fds.as_mut_ptr()) };
+        assert_eq!(rc, 0);
+        let queue = Arc::new(Mutex::new(QueueState::new(
+            VerdictHandle {
+                fd: fds[0],
+                queue_num: 3,
+            },
+            Verdict::Drop,
+        )));
+        let packet = |id| {
+            queue.lock().unwrap().pending.insert(id);
+            NfqPacket {
+                id,
+                payload: Vec::new(),
+                hook: 0,
+                indev: 0,
+                outdev: 0,
+                gso: false,
+                segments: 1,
+                wire_len: 0,
+                arrival: std::time::Instant::now(),
+                uid: None,
+                gid: None,
+                conntrack: None,
+                budget: None,
+                decisions: Vec::new(),
+                queue: Arc::clone(&queue),
+                verdict_sent: false,
+            }
+        };
+
+        packet(7).set_verdict(Verdict::Accept);
+        drop(packet(8));
+
+        // (verdict, id) of the sent verdict headers
+        let mut verdicts = Vec::new();
+        let mut buf = [0u8; 256];
+        loop {
+            let len = unsafe {
+                libc::recv(
+                    fds[1],
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    libc::MSG_DONTWAIT,
+                )
+            };
+            if len <= 0 {
+                break;
+            }
+            for msg in messages(&buf[..len as usize]) {
+                let (_, hdr) = attributes(&msg.payload[NFGENMSG_LEN..])[0];
+                verdicts.push((read_u32_be(hdr), read_u32_be(&hdr[4..])));
+            }
+        }
+        assert_eq!(verdicts, vec![(1, 7), (0, 8)]);
+        assert!(queue.lock().unwrap().pending.is_empty());
+
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// What happens to a packet if the budget is exhausted
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    DropNewest,
+    /// drop the packets which arrived first to make room, of all connections of the
+    /// queue (each queue only evicts its own packets)
+    DropOldest,
+    /// accept the packet immediately without degradation
+    Accept,
+}
+
+/// Global limit for the packets (and their payload bytes) queued by all connections
+/// of all queues, 0 means unlimited.
+pub struct PacketBudget {
+    max_bytes: usize,
+    max_packets: usize,
+    bytes: AtomicUsize,
+    packets: AtomicUsize,
+}
+
+impl PacketBudget {
+    pub fn new(max_bytes: usize, max_packets: usize) -> Arc<Self> {
+        Arc::new(PacketBudget {
+            max_bytes,
+            max_packets,
+            bytes: AtomicUsize::new(0),
+            packets: AtomicUsize::new(0),
+        })
+    }
+
+    /// Reserves room for a packet, it's given back when the guard is dropped
+    pub fn reserve(self: &Arc<Self>, bytes: usize) -> Option<BudgetGuard> {
+        if !try_add(&self.packets, 1, self.max_packets) {
+            return None;
+        }
+        if !try_add(&self.bytes, bytes, self.max_bytes) {
+            self.packets.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(BudgetGuard {
+            budget: Arc::clone(self),
+            bytes,
+        })
+    }
+}
+
+fn try_add(counter: &AtomicUsize, amount: usize, max: usize) -> bool {
+    counter
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |value| {
+            let value = value + amount;
+            if max == 0 || value <= max {
+                Some(value)
+            } else {
+                None
+            }
+        })
+        .is_ok()
+}
+
+/// Budget reservation of a queued packet
+pub struct BudgetGuard {
+    budget: Arc<PacketBudget>,
+    bytes: usize,
+}
+
+impl Drop for BudgetGuard {
+    fn drop(&mut self) {
+        self.budget.packets.fetch_sub(1, Ordering::SeqCst);
+        self.budget.bytes.fetch_sub(self.bytes, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reservations_are_limited_and_given_back() {
+        let budget = PacketBudget::new(3000, 2);
+        let first = budget.reserve(1500).unwrap();
+        let second = budget.reserve(1000).unwrap();
+        // packet limit
+        assert!(budget.reserve(100).is_none());
+        drop(second);
+        // byte limit
+        assert!(budget.reserve(1600).is_none());
+        let third = budget.reserve(1500).unwrap();
+        drop(first);
+        drop(third);
+        assert_eq!(budget.bytes.load(Ordering::SeqCst), 0);
+        assert_eq!(budget.packets.load(Ordering::SeqCst), 0);
+    }
+}
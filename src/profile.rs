@@ -0,0 +1,192 @@
+use crate::config::{parse_model_spec, QueuingModelConfig};
+
+/// Built-in network profile, the models of each direction as model specs in chain
+/// order: bursty loss, bandwidth and buffer, delay and jitter
+pub struct Profile {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub uplink: &'static [&'static str],
+    pub downlink: &'static [&'static str],
+}
+
+pub const PROFILES: [Profile; 9] = [
+    Profile {
+        name: "3g",
+        description: "UMTS/HSPA, 384 kbit/s up, 1.5 Mbit/s down, 100 ms delay",
+        uplink: &[
+            "gilbert_elliott:1,30,30,0",
+            "bandwidth:48,4,64",
+            "random:0,80,120",
+        ],
+        downlink: &[
+            "gilbert_elliott:1,30,30,0",
+            "bandwidth:190,8,256",
+            "random:0,80,120",
+        ],
+    },
+    Profile {
+        name: "lte-good",
+        description: "LTE with good reception, 10 Mbit/s up, 30 Mbit/s down, 20 ms delay",
+        uplink: &[
+            "gilbert_elliott:0.1,50,10,0",
+            "bandwidth:1200,32,512",
+            "random:0,15,25",
+        ],
+        downlink: &[
+            "gilbert_elliott:0.1,50,10,0",
+            "bandwidth:3600,64,1024",
+            "random:0,15,25",
+        ],
+    },
+    Profile {
+        name: "lte-edge",
+        description: "LTE at the cell edge, 500 kbit/s up, 2 Mbit/s down, 40-90 ms delay",
+        uplink: &[
+            "gilbert_elliott:2,20,40,0.5",
+            "bandwidth:60,4,128",
+            "random:0,40,90",
+        ],
+        downlink: &[
+            "gilbert_elliott:2,20,40,0.5",
+            "bandwidth:250,8,256",
+            "random:0,40,90",
+        ],
+    },
+    Profile {
+        name: "dsl",
+        description: "ADSL, 1 Mbit/s up, 16 Mbit/s down, 10 ms delay",
+        uplink: &[
+            "gilbert_elliott:0.05,60,10,0",
+            "bandwidth:120,4,64",
+            "random:0,8,12",
+        ],
+        downlink: &[
+            "gilbert_elliott:0.05,60,10,0",
+            "bandwidth:2000,32,256",
+            "random:0,8,12",
+        ],
+    },
+    Profile {
+        name: "cable",
+        description: "DOCSIS cable, 10 Mbit/s up, 100 Mbit/s down, 10 ms delay",
+        uplink: &[
+            "gilbert_elliott:0.05,50,10,0",
+            "bandwidth:1200,32,512",
+            "random:0,5,15",
+        ],
+        downlink: &[
+            "gilbert_elliott:0.05,50,10,0",
+            "bandwidth:12000,128,2048",
+            "random:0,5,15",
+        ],
+    },
+    Profile {
+        name: "geo-satellite",
+        description: "geostationary satellite, 2 Mbit/s up, 10 Mbit/s down, 300 ms delay",
+        uplink: &[
+            "gilbert_elliott:0.5,30,20,0",
+            "bandwidth:250,16,512",
+            "random:0,280,320",
+        ],
+        downlink: &[
+            "gilbert_elliott:0.5,30,20,0",
+            "bandwidth:1200,32,1024",
+            "random:0,280,320",
+        ],
+    },
+    Profile {
+        name: "leo-satellite",
+        description: "low earth orbit satellite, 10 Mbit/s up, 100 Mbit/s down, 20-45 ms delay with handover losses",
+        uplink: &[
+            "gilbert_elliott:1,25,30,0",
+            "bandwidth:1200,32,512",
+            "random:0,20,45",
+        ],
+        downlink: &[
+            "gilbert_elliott:1,25,30,0",
+            "bandwidth:12000,128,2048",
+            "random:0,20,45",
+        ],
+    },
+    Profile {
+        name: "wifi-congested",
+        description: "crowded WiFi, 2 Mbit/s up, 5 Mbit/s down, 5-60 ms delay",
+        uplink: &[
+            "gilbert_elliott:3,15,25,1",
+            "bandwidth:250,16,256",
+            "random:0,5,60",
+        ],
+        downlink: &[
+            "gilbert_elliott:3,15,25,1",
+            "bandwidth:600,16,256",
+            "random:0,5,60",
+        ],
+    },
+    Profile {
+        name: "edge",
+        description: "GSM EDGE, 200 kbit/s up, 240 kbit/s down, 150-300 ms delay",
+        uplink: &[
+            "gilbert_elliott:2,20,30,0.5",
+            "bandwidth:25,2,32",
+            "random:0,150,300",
+        ],
+        downlink: &[
+            "gilbert_elliott:2,20,30,0.5",
+            "bandwidth:30,2,32",
+            "random:0,150,300",
+        ],
+    },
+];
+
+pub fn find(name: &str) -> Option<&'static Profile> {
+    PROFILES.iter().find(|profile| profile.name == name)
+}
+
+/// Names of all profiles
+pub fn names() -> Vec<&'static str> {
+    PROFILES.iter().map(|profile| profile.name).collect()
+}
+
+impl Profile {
+    /// Uplink and downlink models
+    pub fn models(&self) -> Result<(Vec<QueuingModelConfig>, Vec<QueuingModelConfig>), String> {
+        let parse = |specs: &[&str]| {
+            specs
+                .iter()
+                .map(|spec| parse_model_spec(spec))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok((parse(self.uplink)?, parse(self.downlink)?))
+    }
+}
+
+impl std::fmt::Display for Profile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {}\n  uplink: {}\n  downlink: {}",
+            self.name,
+            self.description,
+            self.uplink.join(" "),
+            self.downlink.join(" ")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn all_profiles_are_valid() {
+        for profile in PROFILES.iter() {
+            let (uplink, downlink) = profile
+                .models()
+                .unwrap_or_else(|e| panic!("invalid profile {}: {}", profile.name, e));
+            assert_eq!(uplink.len(), 3, "{}", profile.name);
+            assert_eq!(downlink.len(), 3, "{}", profile.name);
+        }
+        assert!(find("lte-good").is_some());
+        assert!(find("5g").is_none());
+    }
+}
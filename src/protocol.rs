@@ -1,4 +1,4 @@
-#[derive(Default, PartialEq, Eq, Hash)]
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
 pub struct ProtocolInfo {
     pub source_ip: [u8; 4],
     pub source_port: u16,
@@ -68,6 +68,24 @@ impl std::fmt::Display for ProtocolInfo {
     }
 }
 
+/// Splits a gso packet of `len` bytes into mtu sized segments, returns the number of
+/// segments and their size on the wire. `headers` must contain the ip and transport header.
+pub fn gso_segments(headers: &[u8], len: usize, mtu: usize) -> (u32, usize) {
+    let header_len = match etherparse::PacketHeaders::from_ip_slice(headers) {
+        Ok(parsed) => headers.len() - parsed.payload.len(),
+        Err(_) => return (1, len),
+    };
+
+    if len <= mtu || mtu <= header_len {
+        return (1, len);
+    }
+
+    let segment_payload = mtu - header_len;
+    let payload = len - header_len;
+    let segments = payload.div_ceil(segment_payload);
+    (segments as u32, len + (segments - 1) * header_len)
+}
+
 fn ports_from_ipv4_header(header: &etherparse::PacketHeaders) -> (u16, u16) {
     let transport = header.transport.as_ref();
     match transport {
@@ -80,3 +98,126 @@ fn ports_from_ipv4_header(header: &etherparse::PacketHeaders) -> (u16, u16) {
         None => (0, 0),
     }
 }
+
+// netfilter hooks, see linux/netfilter.h
+const NF_INET_PRE_ROUTING: u8 = 0;
+const NF_INET_LOCAL_IN: u8 = 1;
+const NF_INET_FORWARD: u8 = 2;
+const NF_INET_POST_ROUTING: u8 = 4;
+
+/// Direction of a packet, uplink is traffic leaving the host (or the uplink interface
+/// for routed traffic), downlink is traffic coming in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Uplink,
+    Downlink,
+}
+
+impl Direction {
+    /// Derives the direction from the netfilter hook the packet was queued from.
+    /// With `uplink_dev`, routed packets are considered downlink if they enter via the
+    /// uplink interface (PREROUTING) and uplink if they leave via it (FORWARD, POSTROUTING).
+    pub fn from_hook(hook: u8, indev: u32, outdev: u32, uplink_dev: Option<u32>) -> Self {
+        match (hook, uplink_dev) {
+            (NF_INET_PRE_ROUTING, Some(dev)) if dev != indev => Direction::Uplink,
+            (NF_INET_FORWARD, Some(dev)) | (NF_INET_POST_ROUTING, Some(dev)) if dev != outdev => {
+                Direction::Downlink
+            }
+            (NF_INET_PRE_ROUTING, _) | (NF_INET_LOCAL_IN, _) => Direction::Downlink,
+            _ => Direction::Uplink,
+        }
+    }
+}
+
+impl std::fmt::Display for Direction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Direction::Uplink => write!(f, "uplink"),
+            Direction::Downlink => write!(f, "downlink"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tcp_packet(payload_len: usize) -> Vec<u8> {
+        let builder = etherparse::PacketBuilder::ipv4([10, 0, 0, 1], [10, 0, 0, 2], 64)
+            .tcp(40000, 50000, 1, 1024);
+        let payload = vec![0u8; payload_len];
+        let mut packet = Vec::with_capacity(builder.size(payload_len));
+        builder.write(&mut packet, &payload).unwrap();
+        packet
+    }
+
+    #[test]
+    fn split_gso_packet_into_segments() {
+        // 20 bytes ip + 20 bytes tcp header, 1460 bytes payload per 1500 bytes segment
+        let packet = tcp_packet(14600);
+        assert_eq!(gso_segments(&packet, packet.len(), 1500), (10, 15000));
+
+        let packet = tcp_packet(14601);
+        assert_eq!(
+            gso_segments(&packet, packet.len(), 1500),
+            (11, 14641 + 10 * 40)
+        );
+    }
+
+    #[test]
+    fn no_segments_for_small_packets() {
+        let packet = tcp_packet(100);
+        assert_eq!(gso_segments(&packet, packet.len(), 1500), (1, 140));
+        assert_eq!(gso_segments(&[1, 2, 3], 3000, 1500), (1, 3000));
+    }
+
+    #[test]
+    fn direction_from_hook() {
+        // local out and post routing
+        assert_eq!(Direction::from_hook(3, 0, 0, None), Direction::Uplink);
+        assert_eq!(
+            Direction::from_hook(NF_INET_POST_ROUTING, 0, 2, None),
+            Direction::Uplink
+        );
+        assert_eq!(
+            Direction::from_hook(NF_INET_PRE_ROUTING, 2, 0, None),
+            Direction::Downlink
+        );
+        assert_eq!(
+            Direction::from_hook(NF_INET_LOCAL_IN, 2, 0, Some(2)),
+            Direction::Downlink
+        );
+        assert_eq!(
+            Direction::from_hook(NF_INET_FORWARD, 2, 3, None),
+            Direction::Uplink
+        );
+
+        // routed packets entering or leaving via the uplink interface or another one
+        assert_eq!(
+            Direction::from_hook(NF_INET_PRE_ROUTING, 2, 0, Some(2)),
+            Direction::Downlink
+        );
+        assert_eq!(
+            Direction::from_hook(NF_INET_PRE_ROUTING, 3, 0, Some(2)),
+            Direction::Uplink
+        );
+        assert_eq!(
+            Direction::from_hook(NF_INET_FORWARD, 3, 2, Some(2)),
+            Direction::Uplink
+        );
+        assert_eq!(
+            Direction::from_hook(NF_INET_FORWARD, 2, 3, Some(2)),
+            Direction::Downlink
+        );
+        assert_eq!(
+            Direction::from_hook(NF_INET_POST_ROUTING, 0, 2, Some(2)),
+            Direction::Uplink
+        );
+        assert_eq!(
+            Direction::from_hook(NF_INET_POST_ROUTING, 0, 3, Some(2)),
+            Direction::Downlink
+        );
+        // locally generated packets are always uplink
+        assert_eq!(Direction::from_hook(3, 0, 3, Some(2)), Direction::Uplink);
+    }
+}
@@ -1,7 +1,8 @@
-use super::QueuingModel;
+use super::{Action, Decision, ModelStats, QueuingModel};
+use crate::metrics::{self, DropReason};
 use crate::nfqueue_wrapper::{NfqPacket, Verdict};
 use std::fmt::Display;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 struct TokenBucket {
     token_count: u64, // 1 token is one byte
@@ -34,6 +35,21 @@ impl TokenBucket {
         }
     }
 
+    // time at which enough tokens for a packet of the given size are available
+    fn available_at(&self, packet_size_bytes: u64) -> Option<Duration> {
+        // oversized packets are sent as soon as the bucket is full
+        let needed = packet_size_bytes.min(self.max_tokens);
+        if self.token_count >= needed {
+            return Some(self.last_token_time);
+        }
+        if self.rate == 0 {
+            return None;
+        }
+        let missing = needed - self.token_count;
+        let wait_us = (missing * 1000000).div_ceil(self.rate);
+        Some(self.last_token_time + Duration::from_micros(wait_us))
+    }
+
     fn remove_token(&mut self, packet_size_bytes: u64) -> bool {
         if self.token_count < packet_size_bytes {
             if self.token_count == self.max_tokens {
@@ -50,9 +66,11 @@ impl TokenBucket {
 
 pub struct BandwidthQueuingModel {
     token_bucket: TokenBucket,
-    buffer: Vec<NfqPacket>,
+    // packets with the time they were enqueued
+    buffer: Vec<(NfqPacket, Duration)>,
     current_buffer_size: u64, // in bytes
     max_buffer_size: u64,     // in bytes
+    stats: ModelStats,
 }
 
 impl BandwidthQueuingModel {
@@ -63,23 +81,33 @@ impl BandwidthQueuingModel {
             current_buffer_size: 0,
             max_buffer_size: buffer_size * 1024,
             buffer: Vec::new(),
+            stats: ModelStats::default(),
         }
     }
 }
 
 impl QueuingModel for BandwidthQueuingModel {
-    fn enqueue(&mut self, packet: NfqPacket, _: Duration) {
-        let packet_size = packet.payload.len() as u64;
+    fn enqueue(&mut self, mut packet: NfqPacket, time_now: Duration) {
+        let packet_size = packet.wire_len as u64;
+        self.stats.enqueued += packet.segments as u64;
         if self.max_buffer_size == 0
             || self.max_buffer_size >= (self.current_buffer_size + packet_size)
         {
-            self.buffer.push(packet);
+            packet
+                .decisions
+                .push(Decision::new("bandwidth", Action::Queue));
+            self.buffer.push((packet, time_now));
             self.current_buffer_size += packet_size;
         } else {
             // TODO: implement another prioritization logic???
             // e.g. randomly drop either a packet in buffer or the new one??
             // otherwise connections with higher packet rates have higher prio
             // or if all connections have same packet rate, the first connection may get higher prio
+            self.stats.dropped += packet.segments as u64;
+            metrics::count_drop(DropReason::BufferFull);
+            packet
+                .decisions
+                .push(Decision::new("bandwidth", Action::Drop));
             packet.set_verdict(Verdict::Drop);
         }
     }
@@ -89,9 +117,16 @@ impl QueuingModel for BandwidthQueuingModel {
 
         let mut packets = Vec::<NfqPacket>::new();
         while !self.buffer.is_empty() {
-            let packet_size = self.buffer[0].payload.len() as u64;
+            let packet_size = self.buffer[0].0.wire_len as u64;
             if self.token_bucket.remove_token(packet_size) {
-                let packet = self.buffer.remove(0);
+                let (mut packet, enqueued) = self.buffer.remove(0);
+                let delay = time_now.saturating_sub(enqueued);
+                self.stats.released += packet.segments as u64;
+                self.stats.total_delay += delay * packet.segments;
+                // the queue decision of this model is the last one
+                if let Some(decision) = packet.decisions.last_mut() {
+                    decision.action = Action::Delay(delay);
+                }
                 packets.push(packet);
             } else {
                 break;
@@ -100,7 +135,7 @@ impl QueuingModel for BandwidthQueuingModel {
 
         let total_size = packets
             .iter()
-            .fold(0, |total_size, p| total_size + p.payload.len() as u64);
+            .fold(0, |total_size, p| total_size + p.wire_len as u64);
 
         if self.current_buffer_size < total_size {
             panic!("unexpected buffer size");
@@ -108,6 +143,47 @@ impl QueuingModel for BandwidthQueuingModel {
         self.current_buffer_size -= total_size;
         packets
     }
+
+    fn next_deadline(&self) -> Option<Duration> {
+        let (packet, _) = self.buffer.first()?;
+        self.token_bucket.available_at(packet.wire_len as u64)
+    }
+
+    fn oldest_arrival(&self) -> Option<Instant> {
+        self.buffer.iter().map(|(packet, _)| packet.arrival).min()
+    }
+
+    // packets are buffered in the order they were released by the models before,
+    // which is not necessarily the order of their arrival
+    fn evict(&mut self) -> Option<NfqPacket> {
+        let oldest = (0..self.buffer.len()).min_by_key(|index| self.buffer[*index].0.arrival)?;
+        let (packet, _) = self.buffer.remove(oldest);
+        self.current_buffer_size -= packet.wire_len as u64;
+        self.stats.evicted += packet.segments as u64;
+        Some(packet)
+    }
+
+    fn flush(&mut self) -> Vec<NfqPacket> {
+        let packets: Vec<NfqPacket> = self.buffer.drain(..).map(|(packet, _)| packet).collect();
+        self.current_buffer_size = 0;
+        self.stats.evicted += packets.iter().map(|p| p.segments as u64).sum::<u64>();
+        packets
+    }
+
+    fn stats(&self) -> ModelStats {
+        ModelStats {
+            queued: self
+                .buffer
+                .iter()
+                .map(|(packet, _)| packet.segments as usize)
+                .sum(),
+            ..self.stats
+        }
+    }
+
+    fn token_fill(&self) -> Option<f64> {
+        Some(self.token_bucket.token_count as f64 / self.token_bucket.max_tokens as f64)
+    }
 }
 
 impl Display for BandwidthQueuingModel {
@@ -121,3 +197,28 @@ impl Display for BandwidthQueuingModel {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_available_at_exact_time() {
+        // 1000 bytes per second: one byte per ms
+        let mut bucket = TokenBucket::new(1000, 10000);
+        assert_eq!(bucket.available_at(0), Some(Duration::ZERO));
+        assert_eq!(bucket.available_at(1500), Some(Duration::from_millis(1500)));
+
+        bucket.add_token(Duration::from_millis(1000));
+        assert!(!bucket.remove_token(1500));
+        assert_eq!(bucket.available_at(1500), Some(Duration::from_millis(1500)));
+        bucket.add_token(Duration::from_millis(1500));
+        assert!(bucket.remove_token(1500));
+
+        // packets larger than the burst size wait for a full bucket
+        assert_eq!(
+            bucket.available_at(20000),
+            Some(Duration::from_millis(11500))
+        );
+    }
+}
@@ -0,0 +1,136 @@
+use super::packet_queue::PacketQueue;
+use super::{Action, Decision, ModelStats, QueuingModel, SegmentLoss};
+use crate::metrics::{self, DropReason};
+use crate::nfqueue_wrapper::{NfqPacket, Verdict};
+use rand::{Rng, SeedableRng};
+use std::fmt::Display;
+use std::time::{Duration, Instant};
+
+/// Bursty loss: a good and a bad state with their own loss rate. Before each packet
+/// the state changes with probability p (good to bad) or r (bad to good), so losses
+/// come in bursts of 1/r packets on average.
+pub struct GilbertElliottQueuingModel {
+    // probabilities from 0 to 1
+    p: f64,
+    r: f64,
+    bad_loss: f64,
+    good_loss: f64,
+    bad: bool,
+    rand: rand::rngs::SmallRng,
+    queue: PacketQueue,
+    dropped: u64,
+    segment_loss: SegmentLoss,
+}
+
+impl GilbertElliottQueuingModel {
+    /// All values in %
+    pub fn new(p: f64, r: f64, bad_loss: f64, good_loss: f64) -> Self {
+        Self {
+            p: p / 100.0,
+            r: r / 100.0,
+            bad_loss: bad_loss / 100.0,
+            good_loss: good_loss / 100.0,
+            bad: false,
+            rand: rand::rngs::SmallRng::from_seed([1; 32]),
+            queue: PacketQueue::new(),
+            dropped: 0,
+            segment_loss: SegmentLoss::default(),
+        }
+    }
+
+    fn drop_packet(&mut self) -> bool {
+        let change = if self.bad { self.r } else { self.p };
+        if self.rand.gen::<f64>() < change {
+            self.bad = !self.bad;
+        }
+        let loss = if self.bad {
+            self.bad_loss
+        } else {
+            self.good_loss
+        };
+        self.rand.gen::<f64>() < loss
+    }
+}
+
+impl QueuingModel for GilbertElliottQueuingModel {
+    fn enqueue(&mut self, mut packet: NfqPacket, time_now: Duration) {
+        // one loss decision per segment, a gso packet is dropped as a whole
+        let lost = (0..packet.segments).filter(|_| self.drop_packet()).count() as u32;
+        let drop = self.segment_loss.drop_packet(packet.segments, lost);
+        if !drop {
+            packet.decisions.push(Decision::new(
+                "gilbert_elliott",
+                Action::Delay(Duration::ZERO),
+            ));
+            self.queue.push(packet, time_now, time_now);
+        } else {
+            self.dropped += packet.segments as u64;
+            metrics::count_drop(DropReason::BurstLoss);
+            packet
+                .decisions
+                .push(Decision::new("gilbert_elliott", Action::Drop));
+            packet.set_verdict(Verdict::Drop)
+        }
+    }
+
+    fn dequeue(&mut self, time_now: Duration) -> Vec<NfqPacket> {
+        self.queue.pop(time_now)
+    }
+
+    fn next_deadline(&self) -> Option<Duration> {
+        self.queue.next_send_time()
+    }
+
+    fn oldest_arrival(&self) -> Option<Instant> {
+        self.queue.oldest_arrival()
+    }
+
+    fn evict(&mut self) -> Option<NfqPacket> {
+        self.queue.pop_oldest()
+    }
+
+    fn flush(&mut self) -> Vec<NfqPacket> {
+        self.queue.pop_all()
+    }
+
+    fn stats(&self) -> ModelStats {
+        let stats = self.queue.stats();
+        ModelStats {
+            enqueued: stats.enqueued + self.dropped,
+            dropped: self.dropped,
+            ..stats
+        }
+    }
+}
+
+impl Display for GilbertElliottQueuingModel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "gilbert elliott queuing model, p: {}%, r: {}%, loss bad: {}%, loss good: {}%",
+            self.p * 100.0,
+            self.r * 100.0,
+            self.bad_loss * 100.0,
+            self.good_loss * 100.0
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn losses_come_in_bursts() {
+        // bad state 1/6 of the time, about 10 packets long
+        let mut model = GilbertElliottQueuingModel::new(2.0, 10.0, 60.0, 0.0);
+        let drops: Vec<bool> = (0..100_000).map(|_| model.drop_packet()).collect();
+
+        let loss_rate = drops.iter().filter(|drop| **drop).count() as f64 / drops.len() as f64;
+        assert!((loss_rate - 0.1).abs() < 0.01, "loss rate {}", loss_rate);
+        // a loss is followed by another one far more often than on average
+        let pairs = drops.windows(2).filter(|pair| pair[0] && pair[1]).count() as f64;
+        let conditional = pairs / (loss_rate * drops.len() as f64);
+        assert!(conditional > 0.4, "loss after loss {}", conditional);
+    }
+}
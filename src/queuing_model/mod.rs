@@ -1,14 +1,143 @@
 pub mod bandwidth_queuing_model;
+pub mod gilbert_elliott_queuing_model;
 pub mod packet_queue;
 pub mod pattern_file_queuing_model;
 pub mod queuing_model_chain;
 pub mod random_queuing_model;
+pub mod timing_wheel;
 
 use crate::nfqueue_wrapper::NfqPacket;
 use std::fmt::Display;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 pub trait QueuingModel: Display {
     fn enqueue(&mut self, packet: NfqPacket, time_now: Duration);
     fn dequeue(&mut self, time_now: Duration) -> Vec<NfqPacket>;
+    /// Earliest time at which `dequeue` may release a packet, `None` if nothing is queued
+    fn next_deadline(&self) -> Option<Duration>;
+    /// Arrival of the packet which arrived first, `None` if nothing is queued
+    fn oldest_arrival(&self) -> Option<Instant>;
+    /// Removes the packet which arrived first, to make room if the packet budget is exhausted
+    fn evict(&mut self) -> Option<NfqPacket>;
+    /// Removes all queued packets
+    fn flush(&mut self) -> Vec<NfqPacket> {
+        std::iter::from_fn(|| self.evict()).collect()
+    }
+    /// Packets enqueued, dropped, released and evicted so far and the ones queued now,
+    /// counted in segments
+    fn stats(&self) -> ModelStats;
+    /// Available tokens relative to the burst size, for models with a token bucket
+    fn token_fill(&self) -> Option<f64> {
+        None
+    }
+}
+
+/// What a model did with a packet, recorded in the packet in chain order
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Decision {
+    pub model: &'static str,
+    pub action: Action,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// held until the given time after its enqueue
+    Delay(Duration),
+    /// buffered until the model releases it, becomes a delay on release
+    Queue,
+    Drop,
+}
+
+impl Decision {
+    pub fn new(model: &'static str, action: Action) -> Self {
+        Decision { model, action }
+    }
+}
+
+impl Display for Decision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.action {
+            Action::Delay(delay) => write!(
+                f,
+                "{} delay {:.3} ms",
+                self.model,
+                delay.as_secs_f64() * 1000.0
+            ),
+            Action::Queue => write!(f, "{} queue", self.model),
+            Action::Drop => write!(f, "{} drop", self.model),
+        }
+    }
+}
+
+/// Turns the loss decisions of the single segments of a gso packet into one for the
+/// whole packet, as it can only be dropped as a whole. Lost segments are carried over
+/// until they make up at least half of a packet, so the share of dropped segments
+/// follows the loss decisions instead of dropping every packet with one lost segment.
+#[derive(Default)]
+pub struct SegmentLoss {
+    // lost segments not yet dropped, negative if more were dropped
+    balance: i64,
+}
+
+impl SegmentLoss {
+    pub fn drop_packet(&mut self, segments: u32, lost: u32) -> bool {
+        if segments <= 1 {
+            return lost > 0;
+        }
+        self.balance += lost as i64;
+        if 2 * self.balance >= segments as i64 {
+            self.balance -= segments as i64;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Packet counts of a model since its creation, in segments: a gso packet counts as
+/// the number of packets it is on the wire
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ModelStats {
+    pub enqueued: u64,
+    pub dropped: u64,
+    pub released: u64,
+    /// removed by `evict` or `flush`
+    pub evicted: u64,
+    /// currently held by the model
+    pub queued: usize,
+    /// sum of the time the released packets spent in the model, per segment
+    pub total_delay: Duration,
+}
+
+impl ModelStats {
+    /// Dropped packets in percent of the enqueued ones
+    pub fn loss_percent(&self) -> f64 {
+        if self.enqueued == 0 {
+            return 0.0;
+        }
+        self.dropped as f64 * 100.0 / self.enqueued as f64
+    }
+
+    pub fn average_delay(&self) -> Duration {
+        if self.released == 0 {
+            return Duration::ZERO;
+        }
+        self.total_delay.div_f64(self.released as f64)
+    }
+}
+
+impl Display for ModelStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} enqueued, {} dropped ({:.2}%), {} released, {} evicted, {} queued, avg. delay {:.1} ms",
+            self.enqueued,
+            self.dropped,
+            self.loss_percent(),
+            self.released,
+            self.evicted,
+            self.queued,
+            self.average_delay().as_secs_f64() * 1000.0
+        )
+    }
 }
@@ -1,45 +1,79 @@
+use super::timing_wheel::TimingWheel;
+use super::ModelStats;
 use crate::nfqueue_wrapper::*;
-use std::collections;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+/// Packets ordered by their send time, counting what passes through for the model stats
 pub struct PacketQueue {
-    queue: collections::BTreeMap<Duration, Vec<NfqPacket>>,
+    // packets with the time they were pushed
+    queue: TimingWheel<(NfqPacket, Duration)>,
+    stats: ModelStats,
+    // segments of the queued packets
+    queued: usize,
 }
 
 impl PacketQueue {
     pub fn new() -> PacketQueue {
         PacketQueue {
-            queue: collections::BTreeMap::new(),
+            queue: TimingWheel::new(),
+            stats: ModelStats::default(),
+            queued: 0,
         }
     }
 
-    pub fn push(&mut self, packet: NfqPacket, send_time: Duration) {
-        self.queue
-            .entry(send_time)
-            .or_insert_with(Vec::new)
-            .push(packet);
+    pub fn push(&mut self, packet: NfqPacket, time_now: Duration, send_time: Duration) {
+        self.stats.enqueued += packet.segments as u64;
+        self.queued += packet.segments as usize;
+        self.queue.push((packet, time_now), send_time);
     }
 
-    pub fn pop(&mut self, time_now: Duration) -> Vec<NfqPacket> {
-        let mut packets: Vec<NfqPacket> = Vec::new();
+    pub fn next_send_time(&self) -> Option<Duration> {
+        self.queue.next_expiration()
+    }
+
+    pub fn oldest_arrival(&self) -> Option<Instant> {
+        self.queue.iter().map(|(packet, _)| packet.arrival).min()
+    }
 
-        let times: Vec<_> = self
+    /// Removes the packet which arrived first
+    pub fn pop_oldest(&mut self) -> Option<NfqPacket> {
+        let (packet, _) = self.queue.remove_min_by_key(|(packet, _)| packet.arrival)?;
+        self.stats.evicted += packet.segments as u64;
+        self.queued -= packet.segments as usize;
+        Some(packet)
+    }
+
+    /// Removes all packets, they count as evicted
+    pub fn pop_all(&mut self) -> Vec<NfqPacket> {
+        let packets: Vec<NfqPacket> = self
             .queue
-            .iter()
-            .filter_map(|entry| {
-                if entry.0 <= &time_now {
-                    Some(*entry.0)
-                } else {
-                    None
-                }
-            })
+            .drain()
+            .into_iter()
+            .map(|(packet, _)| packet)
             .collect();
+        self.stats.evicted += packets.iter().map(|p| p.segments as u64).sum::<u64>();
+        self.queued = 0;
+        packets
+    }
 
-        for time in &times {
-            let mut v = self.queue.remove(time).unwrap();
-            packets.append(&mut v);
-        }
-
+    pub fn pop(&mut self, time_now: Duration) -> Vec<NfqPacket> {
+        let packets = self.queue.pop(time_now);
         packets
+            .into_iter()
+            .map(|(packet, pushed)| {
+                self.stats.released += packet.segments as u64;
+                self.queued -= packet.segments as usize;
+                self.stats.total_delay += time_now.saturating_sub(pushed) * packet.segments;
+                packet
+            })
+            .collect()
+    }
+
+    /// Stats of the pushed packets, drops are up to the model
+    pub fn stats(&self) -> ModelStats {
+        ModelStats {
+            queued: self.queued,
+            ..self.stats
+        }
     }
 }
@@ -1,10 +1,11 @@
 use super::packet_queue::PacketQueue;
-use super::QueuingModel;
+use super::{Action, Decision, ModelStats, QueuingModel, SegmentLoss};
+use crate::metrics::{self, DropReason};
 use crate::nfqueue_wrapper::{NfqPacket, Verdict};
 use csv::{Error, ReaderBuilder, Trim};
 use std::fmt::Display;
 use std::path::Path;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 #[derive(Clone)]
 pub struct PacketInfo {
@@ -17,6 +18,8 @@ pub struct PatternFileQueuingModel {
     is_first_packet: bool,
     curr_packet_no: usize,
     queue: PacketQueue,
+    dropped: u64,
+    segment_loss: SegmentLoss,
 }
 
 impl PatternFileQueuingModel {
@@ -45,6 +48,8 @@ impl PatternFileQueuingModel {
             is_first_packet: true,
             curr_packet_no: 0,
             queue: PacketQueue::new(),
+            dropped: 0,
+            segment_loss: SegmentLoss::default(),
         }
     }
 
@@ -66,11 +71,23 @@ impl PatternFileQueuingModel {
 }
 
 impl QueuingModel for PatternFileQueuingModel {
-    fn enqueue(&mut self, packet: NfqPacket, time_now: Duration) {
-        if !self.drop_packet() {
+    fn enqueue(&mut self, mut packet: NfqPacket, time_now: Duration) {
+        // a gso packet consumes one pattern entry per segment and is dropped as a whole
+        let lost = (0..packet.segments).filter(|_| self.drop_packet()).count() as u32;
+        let drop = self.segment_loss.drop_packet(packet.segments, lost);
+        if !drop {
             let send_time = self.get_send_time(time_now);
-            self.queue.push(packet, send_time);
+            packet.decisions.push(Decision::new(
+                "pattern_file",
+                Action::Delay(send_time - time_now),
+            ));
+            self.queue.push(packet, time_now, send_time);
         } else {
+            self.dropped += packet.segments as u64;
+            metrics::count_drop(DropReason::PatternLoss);
+            packet
+                .decisions
+                .push(Decision::new("pattern_file", Action::Drop));
             packet.set_verdict(Verdict::Drop);
         }
     }
@@ -78,6 +95,31 @@ impl QueuingModel for PatternFileQueuingModel {
     fn dequeue(&mut self, time_now: Duration) -> Vec<NfqPacket> {
         self.queue.pop(time_now)
     }
+
+    fn next_deadline(&self) -> Option<Duration> {
+        self.queue.next_send_time()
+    }
+
+    fn oldest_arrival(&self) -> Option<Instant> {
+        self.queue.oldest_arrival()
+    }
+
+    fn evict(&mut self) -> Option<NfqPacket> {
+        self.queue.pop_oldest()
+    }
+
+    fn flush(&mut self) -> Vec<NfqPacket> {
+        self.queue.pop_all()
+    }
+
+    fn stats(&self) -> ModelStats {
+        let stats = self.queue.stats();
+        ModelStats {
+            enqueued: stats.enqueued + self.dropped,
+            dropped: self.dropped,
+            ..stats
+        }
+    }
 }
 
 impl Display for PatternFileQueuingModel {
@@ -1,33 +1,65 @@
 use super::bandwidth_queuing_model::BandwidthQueuingModel;
+use super::gilbert_elliott_queuing_model::GilbertElliottQueuingModel;
 use super::pattern_file_queuing_model::PatternFileQueuingModel;
 use super::random_queuing_model::RandomQueuingModel;
-use super::QueuingModel;
+use super::{ModelStats, QueuingModel};
 use crate::config::QueuingModelConfig;
 use crate::nfqueue_wrapper::NfqPacket;
 
 use std::fmt::Display;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 struct ForwardingQueuingModel {
     packets: Vec<NfqPacket>,
+    stats: ModelStats,
 }
 
 impl ForwardingQueuingModel {
     pub fn new() -> ForwardingQueuingModel {
         ForwardingQueuingModel {
             packets: Vec::new(),
+            stats: ModelStats::default(),
         }
     }
 }
 
 impl QueuingModel for ForwardingQueuingModel {
     fn enqueue(&mut self, packet: NfqPacket, _: Duration) {
+        self.stats.enqueued += packet.segments as u64;
         self.packets.push(packet);
     }
 
+    fn next_deadline(&self) -> Option<Duration> {
+        // everything is forwarded as soon as possible
+        if self.packets.is_empty() {
+            None
+        } else {
+            Some(Duration::ZERO)
+        }
+    }
+
+    fn oldest_arrival(&self) -> Option<Instant> {
+        self.packets.iter().map(|packet| packet.arrival).min()
+    }
+
+    fn evict(&mut self) -> Option<NfqPacket> {
+        let oldest = (0..self.packets.len()).min_by_key(|index| self.packets[*index].arrival)?;
+        let packet = self.packets.remove(oldest);
+        self.stats.evicted += packet.segments as u64;
+        Some(packet)
+    }
+
     fn dequeue(&mut self, _: Duration) -> Vec<NfqPacket> {
+        self.stats.released += segments(&self.packets);
         self.packets.split_off(0)
     }
+
+    fn stats(&self) -> ModelStats {
+        ModelStats {
+            queued: segments(&self.packets) as usize,
+            ..self.stats
+        }
+    }
 }
 
 impl Display for ForwardingQueuingModel {
@@ -38,6 +70,10 @@ impl Display for ForwardingQueuingModel {
 
 pub struct QueuingModelChain {
     models: Vec<Box<dyn QueuingModel>>,
+    // replaced models, they release their queued packets as scheduled
+    retired: Vec<QueuingModelChain>,
+    // counted for the whole chain, delay is the time since the arrival of the packets
+    stats: ModelStats,
 }
 
 impl QueuingModelChain {
@@ -56,6 +92,9 @@ impl QueuingModelChain {
                 QueuingModelConfig::PatternFile(cfg) => {
                     Box::new(PatternFileQueuingModel::new(&cfg.packet_info))
                 }
+                QueuingModelConfig::GilbertElliott(cfg) => Box::new(
+                    GilbertElliottQueuingModel::new(cfg.p, cfg.r, cfg.bad_loss, cfg.good_loss),
+                ),
             })
             .inspect(|model| log::info!("created {}", model))
             .collect();
@@ -65,12 +104,44 @@ impl QueuingModelChain {
             models.push(Box::new(ForwardingQueuingModel::new()));
         }
 
-        QueuingModelChain { models }
+        QueuingModelChain {
+            models,
+            retired: Vec::new(),
+            stats: ModelStats::default(),
+        }
+    }
+
+    /// Replaces the models, new packets are queued by the new models while packets
+    /// already queued are released by the old ones.
+    pub fn replace(&mut self, config: &[QueuingModelConfig]) {
+        let mut old = std::mem::replace(self, QueuingModelChain::new(config));
+        self.retired = std::mem::take(&mut old.retired);
+        self.stats = old.stats;
+        if old.next_deadline().is_some() {
+            self.retired.push(old);
+        }
+    }
+
+    /// Statistics of the models in chain order, without the replaced chains
+    pub fn model_stats(&self) -> Vec<(String, ModelStats)> {
+        self.models
+            .iter()
+            .map(|model| (model.to_string(), model.stats()))
+            .collect()
+    }
+
+    /// Token bucket fill of the bandwidth models, in chain order
+    pub fn token_fills(&self) -> Vec<f64> {
+        self.models
+            .iter()
+            .filter_map(|model| model.token_fill())
+            .collect()
     }
 }
 
 impl QueuingModel for QueuingModelChain {
     fn enqueue(&mut self, packet: NfqPacket, time_now: Duration) {
+        self.stats.enqueued += packet.segments as u64;
         let m = self.models.first_mut().unwrap();
         m.enqueue(packet, time_now);
     }
@@ -83,12 +154,159 @@ impl QueuingModel for QueuingModelChain {
             }
             packets = model.dequeue(time_now);
         }
+        for chain in self.retired.iter_mut() {
+            packets.append(&mut chain.dequeue(time_now));
+        }
+        self.retired.retain(|chain| chain.next_deadline().is_some());
+        self.stats.released += segments(&packets);
+        self.stats.total_delay += packets
+            .iter()
+            .map(|packet| packet.arrival.elapsed() * packet.segments)
+            .sum::<Duration>();
+        packets
+    }
+
+    fn next_deadline(&self) -> Option<Duration> {
+        self.models
+            .iter()
+            .filter_map(|model| model.next_deadline())
+            .chain(
+                self.retired
+                    .iter()
+                    .filter_map(|chain| chain.next_deadline()),
+            )
+            .min()
+    }
+
+    fn oldest_arrival(&self) -> Option<Instant> {
+        self.models
+            .iter()
+            .filter_map(|model| model.oldest_arrival())
+            .chain(
+                self.retired
+                    .iter()
+                    .filter_map(|chain| chain.oldest_arrival()),
+            )
+            .min()
+    }
+
+    // from the model or replaced chain holding the packet which arrived first
+    fn evict(&mut self) -> Option<NfqPacket> {
+        let (_, model) = self
+            .models
+            .iter_mut()
+            .map(|model| model.as_mut() as &mut dyn QueuingModel)
+            .chain(
+                self.retired
+                    .iter_mut()
+                    .map(|chain| chain as &mut dyn QueuingModel),
+            )
+            .filter_map(|model| Some((model.oldest_arrival()?, model)))
+            .min_by_key(|(arrival, _)| *arrival)?;
+        let packet = model.evict()?;
+        self.stats.evicted += packet.segments as u64;
+        Some(packet)
+    }
+
+    fn flush(&mut self) -> Vec<NfqPacket> {
+        let mut packets: Vec<NfqPacket> = self
+            .models
+            .iter_mut()
+            .flat_map(|model| model.flush())
+            .collect();
+        for mut chain in self.retired.drain(..) {
+            packets.append(&mut chain.flush());
+        }
+        self.stats.evicted += segments(&packets);
         packets
     }
+
+    // delay is measured from the arrival in the degrader, packets neither released,
+    // evicted nor queued were dropped by one of the models
+    fn stats(&self) -> ModelStats {
+        let queued = self
+            .models
+            .iter()
+            .map(|model| model.stats().queued)
+            .chain(self.retired.iter().map(|chain| chain.stats().queued))
+            .sum();
+        ModelStats {
+            queued,
+            dropped: self
+                .stats
+                .enqueued
+                .saturating_sub(self.stats.released + self.stats.evicted + queued as u64),
+            ..self.stats
+        }
+    }
+}
+
+fn segments(packets: &[NfqPacket]) -> u64 {
+    packets.iter().map(|packet| packet.segments as u64).sum()
 }
 
 impl Display for QueuingModelChain {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "chain with {} queueing models", self.models.len())
+        for (index, model) in self.models.iter().enumerate() {
+            if index > 0 {
+                write!(f, " -> ")?;
+            }
+            write!(f, "{}", model)?;
+        }
+        if !self.retired.is_empty() {
+            write!(f, " ({} replaced chains draining)", self.retired.len())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_model_spec;
+    use crate::nfqueue_wrapper::Verdict;
+
+    #[test]
+    fn count_stats_of_the_whole_chain() {
+        let start = Instant::now();
+        let config = [
+            parse_model_spec("random:10,10,10").unwrap(),
+            parse_model_spec("random:0,0,0").unwrap(),
+        ];
+        let mut chain = QueuingModelChain::new(&config);
+        for id in 0..1000 {
+            chain.enqueue(NfqPacket::detached(id, 1), Duration::ZERO);
+        }
+        for _ in 0..10 {
+            chain.evict().unwrap().set_verdict(Verdict::Accept);
+        }
+        let released = chain
+            .dequeue(Duration::from_millis(10))
+            .into_iter()
+            .map(|packet| packet.set_verdict(Verdict::Accept))
+            .count();
+
+        let stats = chain.stats();
+        let models = chain.model_stats();
+        assert_eq!(stats.enqueued, 1000);
+        assert_eq!(stats.evicted, 10);
+        assert_eq!(stats.released, released as u64);
+        assert_eq!(stats.queued, 0);
+        // the drops of the first model
+        assert_eq!(stats.dropped, models[0].1.dropped);
+        assert_eq!(
+            stats.dropped + stats.released + stats.evicted,
+            stats.enqueued
+        );
+        assert!(
+            (stats.loss_percent() - 10.0).abs() < 3.0,
+            "loss {}",
+            stats.loss_percent()
+        );
+        // the second model released all packets of the first one
+        assert_eq!(models[1].1.enqueued, models[0].1.released);
+        assert_eq!(models[1].1.released, stats.released);
+        // delay since the arrival of the packets, not the model time
+        assert!(stats.total_delay <= start.elapsed() * stats.released as u32);
     }
 }
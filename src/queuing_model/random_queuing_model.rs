@@ -1,5 +1,6 @@
 use super::packet_queue::PacketQueue;
-use super::QueuingModel;
+use super::{Action, Decision, ModelStats, QueuingModel, SegmentLoss};
+use crate::metrics::{self, DropReason};
 use crate::nfqueue_wrapper::{NfqPacket, Verdict};
 use rand::{
     distributions::{Distribution, Uniform},
@@ -7,13 +8,15 @@ use rand::{
 };
 use std::fmt::Display;
 use std::ops::RangeInclusive;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 pub struct RandomQueuingModel {
     loss_rate: u32,
     delay: Delay,
     rand: rand::rngs::SmallRng,
     queue: PacketQueue,
+    dropped: u64,
+    segment_loss: SegmentLoss,
 }
 
 impl RandomQueuingModel {
@@ -23,6 +26,8 @@ impl RandomQueuingModel {
             delay: Delay::default(),
             rand: rand::rngs::SmallRng::from_seed([1; 32]),
             queue: PacketQueue::new(),
+            dropped: 0,
+            segment_loss: SegmentLoss::default(),
         }
     }
 
@@ -54,11 +59,20 @@ impl RandomQueuingModel {
 }
 
 impl QueuingModel for RandomQueuingModel {
-    fn enqueue(&mut self, packet: NfqPacket, time_now: Duration) {
-        if !self.drop_packet() {
+    fn enqueue(&mut self, mut packet: NfqPacket, time_now: Duration) {
+        // one loss decision per segment, a gso packet is dropped as a whole
+        let lost = (0..packet.segments).filter(|_| self.drop_packet()).count() as u32;
+        let drop = self.segment_loss.drop_packet(packet.segments, lost);
+        if !drop {
             let send_time = self.get_send_time(time_now);
-            self.queue.push(packet, send_time);
+            packet
+                .decisions
+                .push(Decision::new("random", Action::Delay(send_time - time_now)));
+            self.queue.push(packet, time_now, send_time);
         } else {
+            self.dropped += packet.segments as u64;
+            metrics::count_drop(DropReason::RandomLoss);
+            packet.decisions.push(Decision::new("random", Action::Drop));
             packet.set_verdict(Verdict::Drop)
         }
     }
@@ -66,6 +80,31 @@ impl QueuingModel for RandomQueuingModel {
     fn dequeue(&mut self, time_now: Duration) -> Vec<NfqPacket> {
         self.queue.pop(time_now)
     }
+
+    fn next_deadline(&self) -> Option<Duration> {
+        self.queue.next_send_time()
+    }
+
+    fn oldest_arrival(&self) -> Option<Instant> {
+        self.queue.oldest_arrival()
+    }
+
+    fn evict(&mut self) -> Option<NfqPacket> {
+        self.queue.pop_oldest()
+    }
+
+    fn flush(&mut self) -> Vec<NfqPacket> {
+        self.queue.pop_all()
+    }
+
+    fn stats(&self) -> ModelStats {
+        let stats = self.queue.stats();
+        ModelStats {
+            enqueued: stats.enqueued + self.dropped,
+            dropped: self.dropped,
+            ..stats
+        }
+    }
 }
 
 impl Display for RandomQueuingModel {
@@ -210,4 +249,63 @@ mod tests {
         }
         assert_eq!(drop_counter, 0);
     }
+
+    #[test]
+    fn count_stats() {
+        let delay = Duration::from_millis(10);
+        let mut model = RandomQueuingModel::new(10).with_delay(delay);
+        for id in 0..1000 {
+            model.enqueue(NfqPacket::detached(id, 1), Duration::ZERO);
+        }
+        for _ in 0..10 {
+            model.evict().unwrap().set_verdict(Verdict::Accept);
+        }
+        assert!(model.dequeue(delay / 2).is_empty());
+        let queued = model.stats().queued;
+        let released = model
+            .dequeue(delay)
+            .into_iter()
+            .map(|packet| packet.set_verdict(Verdict::Accept))
+            .count();
+
+        let stats = model.stats();
+        assert_eq!(stats.enqueued, 1000);
+        assert_eq!(stats.evicted, 10);
+        assert_eq!(stats.released, released as u64);
+        assert_eq!(stats.released, queued as u64);
+        assert_eq!(
+            stats.dropped + stats.released + stats.evicted,
+            stats.enqueued
+        );
+        assert_eq!(stats.queued, 0);
+        assert_eq!(stats.total_delay, delay * stats.released as u32);
+        assert_eq!(stats.average_delay(), delay);
+        assert!(
+            (stats.loss_percent() - 10.0).abs() < 3.0,
+            "loss {}",
+            stats.loss_percent()
+        );
+    }
+
+    #[test]
+    fn gso_packets_lose_the_configured_share_of_segments() {
+        let mut model = RandomQueuingModel::new(10);
+        for id in 0..5000 {
+            model.enqueue(NfqPacket::detached(id, 40), Duration::ZERO);
+        }
+        for packet in model.dequeue(Duration::ZERO) {
+            packet.set_verdict(Verdict::Accept);
+        }
+
+        let stats = model.stats();
+        assert_eq!(stats.enqueued, 5000 * 40);
+        assert_eq!(stats.dropped + stats.released, stats.enqueued);
+        // gso packets are dropped as a whole
+        assert_eq!(stats.dropped % 40, 0);
+        assert!(
+            (stats.loss_percent() - 10.0).abs() < 1.0,
+            "loss {}",
+            stats.loss_percent()
+        );
+    }
 }
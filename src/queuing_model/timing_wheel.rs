@@ -0,0 +1,270 @@
+use std::convert::TryFrom;
+use std::time::Duration;
+
+// 64 slots per level, each level covers 64 times the range of the level below.
+// A tick is one nanosecond, 11 levels cover the whole u64 tick range.
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const SLOT_MASK: u64 = SLOTS as u64 - 1;
+
+struct Entry<T> {
+    tick: u64,
+    item: T,
+}
+
+struct Level<T> {
+    level: u32,
+    // bit n is set if slot n holds entries
+    occupied: u64,
+    slots: Vec<Vec<Entry<T>>>,
+}
+
+impl<T> Level<T> {
+    fn new(level: u32) -> Self {
+        Level {
+            level,
+            occupied: 0,
+            slots: (0..SLOTS).map(|_| Vec::new()).collect(),
+        }
+    }
+
+    fn slot_range(&self) -> u64 {
+        1 << (self.level * SLOT_BITS)
+    }
+
+    fn slot_for(&self, tick: u64) -> usize {
+        ((tick >> (self.level * SLOT_BITS)) & SLOT_MASK) as usize
+    }
+
+    fn push(&mut self, entry: Entry<T>) {
+        let slot = self.slot_for(entry.tick);
+        self.slots[slot].push(entry);
+        self.occupied |= 1 << slot;
+    }
+
+    fn take(&mut self, slot: usize) -> Vec<Entry<T>> {
+        self.occupied &= !(1 << slot);
+        std::mem::take(&mut self.slots[slot])
+    }
+
+    // start tick of the next occupied slot after `now`
+    fn next_expiration(&self, now: u64) -> Option<(u64, usize)> {
+        if self.occupied == 0 {
+            return None;
+        }
+        let slot_range = self.slot_range();
+        let now_slot = self.slot_for(now);
+        let zeros = self.occupied.rotate_right(now_slot as u32).trailing_zeros() as usize;
+        let slot = (now_slot + zeros) % SLOTS;
+
+        // the top level covers the whole tick range
+        let level_start = match slot_range.checked_mul(SLOTS as u64) {
+            Some(level_range) => now & !(level_range - 1),
+            None => 0,
+        };
+        Some((level_start + slot as u64 * slot_range, slot))
+    }
+}
+
+/// Hierarchical timing wheel with O(1) insertion and amortized O(1) expiry.
+///
+/// Items are due at a `Duration` (relative to the same clock as the times passed
+/// to `pop`), they are returned in the order of their time.
+pub struct TimingWheel<T> {
+    // current tick of the wheel, all slots before it have been expired
+    elapsed: u64,
+    levels: Vec<Level<T>>,
+    // items that are due but not yet popped
+    expired: Vec<Entry<T>>,
+}
+
+impl<T> TimingWheel<T> {
+    pub fn new() -> Self {
+        TimingWheel {
+            elapsed: 0,
+            levels: Vec::new(),
+            expired: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, item: T, time: Duration) {
+        let tick = u64::try_from(time.as_nanos()).unwrap_or(u64::MAX);
+        self.insert(Entry { tick, item });
+    }
+
+    /// Time of the next wheel slot to expire, which may be before the due time of its items
+    pub fn next_expiration(&self) -> Option<Duration> {
+        self.expired
+            .iter()
+            .map(|entry| entry.tick)
+            .min()
+            .or_else(|| self.next_slot().map(|(tick, _, _)| tick))
+            .map(Duration::from_nanos)
+    }
+
+    /// Removes all items due at `time_now`, ordered by their time.
+    pub fn pop(&mut self, time_now: Duration) -> Vec<T> {
+        let now = u64::try_from(time_now.as_nanos()).unwrap_or(u64::MAX);
+        while let Some((tick, level, slot)) = self.next_slot() {
+            if tick > now {
+                break;
+            }
+            self.elapsed = tick;
+            // cascade the slot down, items due at this tick expire
+            for entry in self.levels[level].take(slot) {
+                self.insert(entry);
+            }
+        }
+        self.elapsed = self.elapsed.max(now);
+
+        let mut expired = std::mem::take(&mut self.expired);
+        expired.sort_by_key(|entry| entry.tick);
+        expired.into_iter().map(|entry| entry.item).collect()
+    }
+
+    /// All items, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.expired
+            .iter()
+            .chain(
+                self.levels
+                    .iter()
+                    .flat_map(|level| level.slots.iter().flatten()),
+            )
+            .map(|entry| &entry.item)
+    }
+
+    /// Removes the item with the smallest key, regardless of the time. Takes linear time.
+    pub fn remove_min_by_key<K: Ord>(&mut self, key: impl Fn(&T) -> K) -> Option<T> {
+        // (level, slot, index) of the item, no level for expired items
+        let (level, slot, index) = self
+            .expired
+            .iter()
+            .enumerate()
+            .map(|(index, entry)| (None, 0, index, entry))
+            .chain(self.levels.iter().enumerate().flat_map(|(level, slots)| {
+                slots
+                    .slots
+                    .iter()
+                    .enumerate()
+                    .flat_map(move |(slot, entries)| {
+                        entries
+                            .iter()
+                            .enumerate()
+                            .map(move |(index, entry)| (Some(level), slot, index, entry))
+                    })
+            }))
+            .min_by_key(|(_, _, _, entry)| key(&entry.item))
+            .map(|(level, slot, index, _)| (level, slot, index))?;
+
+        let entry = match level {
+            None => self.expired.swap_remove(index),
+            Some(level) => {
+                let level = &mut self.levels[level];
+                let entry = level.slots[slot].swap_remove(index);
+                if level.slots[slot].is_empty() {
+                    level.occupied &= !(1 << slot);
+                }
+                entry
+            }
+        };
+        Some(entry.item)
+    }
+
+    /// Removes all items, regardless of the time.
+    pub fn drain(&mut self) -> Vec<T> {
+        let mut entries = std::mem::take(&mut self.expired);
+        for level in self.levels.iter_mut() {
+            for slot in 0..SLOTS {
+                entries.append(&mut level.take(slot));
+            }
+        }
+        entries.sort_by_key(|entry| entry.tick);
+        entries.into_iter().map(|entry| entry.item).collect()
+    }
+
+    fn insert(&mut self, entry: Entry<T>) {
+        if entry.tick <= self.elapsed {
+            self.expired.push(entry);
+            return;
+        }
+        // the level is given by the highest 6 bit block in which tick and elapsed differ
+        let masked = (self.elapsed ^ entry.tick) | SLOT_MASK;
+        let level = (63 - masked.leading_zeros()) / SLOT_BITS;
+        while self.levels.len() <= level as usize {
+            self.levels.push(Level::new(self.levels.len() as u32));
+        }
+        self.levels[level as usize].push(entry);
+    }
+
+    // (tick, level, slot) of the earliest occupied slot
+    fn next_slot(&self) -> Option<(u64, usize, usize)> {
+        self.levels
+            .iter()
+            .enumerate()
+            .filter_map(|(index, level)| {
+                level
+                    .next_expiration(self.elapsed)
+                    .map(|(tick, slot)| (tick, index, slot))
+            })
+            .min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn pop_due_items_in_time_order() {
+        let mut rand = rand::rngs::SmallRng::from_seed([3; 32]);
+        let times: Vec<Duration> = (0..2000)
+            .map(|_| Duration::from_nanos(rand.gen_range(0..20_000_000_000)))
+            .collect();
+        let mut wheel = TimingWheel::new();
+        for (index, time) in times.iter().enumerate() {
+            wheel.push(index, *time);
+        }
+
+        let mut now = Duration::ZERO;
+        let mut popped: Vec<usize> = Vec::new();
+        while let Some(next) = wheel.next_expiration() {
+            assert!(next >= now);
+            now = next + Duration::from_nanos(rand.gen_range(0..3_000_000));
+            popped.extend(wheel.pop(now));
+            // everything due has been popped, nothing early
+            assert!(popped.iter().all(|index| times[*index] <= now));
+            assert_eq!(
+                popped.len(),
+                times.iter().filter(|time| **time <= now).count()
+            );
+        }
+        assert_eq!(popped.len(), times.len());
+        assert!(popped.windows(2).all(|w| times[w[0]] <= times[w[1]]));
+    }
+
+    #[test]
+    fn remove_items_regardless_of_time() {
+        let mut wheel = TimingWheel::new();
+        // items in arrival order, due in a different order
+        for (item, ms) in [(1, 300), (2, 20), (3, 5000), (4, 10), (5, 40)] {
+            wheel.push(item, Duration::from_millis(ms));
+        }
+        assert_eq!(wheel.pop(Duration::from_millis(15)), vec![4]);
+        assert_eq!(wheel.iter().count(), 4);
+        let mut items: Vec<_> = wheel.iter().copied().collect();
+        items.sort_unstable();
+        assert_eq!(items, vec![1, 2, 3, 5]);
+
+        assert_eq!(wheel.remove_min_by_key(|item| *item), Some(1));
+        assert_eq!(wheel.remove_min_by_key(|item| *item), Some(2));
+        // the slot of the removed items is free
+        assert!(wheel.next_expiration() > Some(Duration::from_millis(20)));
+        assert_eq!(wheel.iter().count(), 2);
+        assert_eq!(wheel.drain(), vec![5, 3]);
+        assert_eq!(wheel.remove_min_by_key(|item| *item), None);
+        assert_eq!(wheel.next_expiration(), None);
+        assert_eq!(wheel.iter().count(), 0);
+    }
+}
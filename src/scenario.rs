@@ -0,0 +1,285 @@
+use crate::config::{parse_model_spec, Phase, QueuingModelConfig};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+// value of a setting, the same for toml and yaml
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Value {
+    Bool(bool),
+    Integer(i64),
+    Float(f64),
+    Text(String),
+    List(Vec<Value>),
+    Table(BTreeMap<String, Value>),
+}
+
+// settings taking a list of values
+const LIST_SETTINGS: [&str; 2] = ["match", "phase"];
+
+/// Setup read from a toml or yaml scenario file. Models and phases are given as
+/// lists, all other settings have the name and values of the cli options.
+#[derive(Default)]
+pub struct Scenario {
+    /// cli option names with their values
+    pub settings: BTreeMap<String, Vec<String>>,
+    pub models: Vec<QueuingModelConfig>,
+    pub uplink_models: Vec<QueuingModelConfig>,
+    pub downlink_models: Vec<QueuingModelConfig>,
+    pub phases: Vec<Phase>,
+}
+
+impl Scenario {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let content =
+            std::fs::read_to_string(path).map_err(|e| format!("failed to read file: {}", e))?;
+        match Path::new(path)
+            .extension()
+            .and_then(|extension| extension.to_str())
+        {
+            Some("toml") => Scenario::parse_toml(&content),
+            Some("yaml") | Some("yml") => Scenario::parse_yaml(&content),
+            _ => Err("unknown format, expected a .toml, .yaml or .yml file".to_string()),
+        }
+    }
+
+    pub fn parse_toml(content: &str) -> Result<Self, String> {
+        Scenario::from_settings(toml::from_str(content).map_err(|e| e.to_string())?)
+    }
+
+    pub fn parse_yaml(content: &str) -> Result<Self, String> {
+        Scenario::from_settings(serde_yaml::from_str(content).map_err(|e| e.to_string())?)
+    }
+
+    fn from_settings(settings: BTreeMap<String, Value>) -> Result<Self, String> {
+        let mut scenario = Scenario::default();
+        for (name, value) in settings {
+            match name.as_str() {
+                "models" => scenario.models = models(&name, &value)?,
+                "uplink_models" => scenario.uplink_models = models(&name, &value)?,
+                "downlink_models" => scenario.downlink_models = models(&name, &value)?,
+                "phases" => scenario.phases = phases(&value)?,
+                "config" => return Err("config can't be set in a scenario".to_string()),
+                _ => {
+                    let values = match &value {
+                        Value::List(values) if LIST_SETTINGS.contains(&name.as_str()) => values
+                            .iter()
+                            .map(|value| scalar(&name, value))
+                            .collect::<Result<_, _>>()?,
+                        value => vec![scalar(&name, value)?],
+                    };
+                    scenario.settings.insert(name, values);
+                }
+            }
+        }
+        Ok(scenario)
+    }
+}
+
+fn scalar(name: &str, value: &Value) -> Result<String, String> {
+    match value {
+        Value::Bool(value) => Ok(value.to_string()),
+        Value::Integer(value) => Ok(value.to_string()),
+        Value::Float(value) => Ok(value.to_string()),
+        Value::Text(value) => Ok(value.clone()),
+        _ => Err(format!("{} must be a value", name)),
+    }
+}
+
+// list of model specs (`bandwidth:1000,100,1000`) or tables (`type = "bandwidth"`, ...)
+fn models(name: &str, value: &Value) -> Result<Vec<QueuingModelConfig>, String> {
+    let models = match value {
+        Value::List(models) => models,
+        _ => return Err(format!("{} must be a list of models", name)),
+    };
+    models
+        .iter()
+        .enumerate()
+        .map(|(index, model)| {
+            let spec = match model {
+                Value::Text(spec) => Ok(spec.clone()),
+                Value::Table(params) => model_spec(params),
+                _ => Err("expected a model spec or a table with the model type".to_string()),
+            };
+            spec.and_then(|spec| parse_model_spec(&spec))
+                .map_err(|e| format!("{}[{}]: {}", name, index, e))
+        })
+        .collect()
+}
+
+fn model_spec(params: &BTreeMap<String, Value>) -> Result<String, String> {
+    let model = match params.get("type") {
+        Some(Value::Text(model)) => model.as_str(),
+        _ => return Err("missing model type".to_string()),
+    };
+    // parameters with their default
+    let names: &[(&str, Option<i64>)] = match model {
+        "random" => &[
+            ("loss", None),
+            ("delay_min", Some(0)),
+            ("delay_max", Some(0)),
+        ],
+        "bandwidth" => &[("rate", None), ("burst", None), ("buffer", None)],
+        "gilbert_elliott" => &[
+            ("p", None),
+            ("r", None),
+            ("bad_loss", None),
+            ("good_loss", Some(0)),
+        ],
+        "pattern_file" => {
+            return match params.get("file") {
+                Some(Value::Text(file)) if params.len() == 2 => {
+                    Ok(format!("pattern_file:{}", file))
+                }
+                Some(Value::Text(_)) => Err("pattern_file only has the parameter file".to_string()),
+                _ => Err("pattern_file needs a file".to_string()),
+            };
+        }
+        _ => {
+            return Err(format!(
+                "unknown model {}, expected random, bandwidth, pattern_file or gilbert_elliott",
+                model
+            ))
+        }
+    };
+    if let Some(unknown) = params
+        .keys()
+        .find(|key| *key != "type" && names.iter().all(|(name, _)| name != key))
+    {
+        return Err(format!("unknown parameter {} of {}", unknown, model));
+    }
+    let values = names
+        .iter()
+        .map(|(name, default)| match (params.get(*name), default) {
+            (Some(Value::Integer(value)), _) if *value >= 0 => Ok(value.to_string()),
+            // probabilities in %
+            (Some(Value::Float(value)), _) if model == "gilbert_elliott" && *value >= 0.0 => {
+                Ok(value.to_string())
+            }
+            (Some(_), _) if model == "gilbert_elliott" => {
+                Err(format!("{} of {} must be a positive number", name, model))
+            }
+            (Some(_), _) => Err(format!("{} of {} must be a positive integer", name, model)),
+            (None, Some(default)) => Ok(default.to_string()),
+            (None, None) => Err(format!("{} needs {}", model, name)),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(format!("{}:{}", model, values.join(",")))
+}
+
+// phase names with their models for both directions, or a table with uplink and downlink models
+fn phases(value: &Value) -> Result<Vec<Phase>, String> {
+    let phases = match value {
+        Value::Table(phases) => phases,
+        _ => return Err("phases must be a table of phase names and their models".to_string()),
+    };
+    phases
+        .iter()
+        .map(|(name, value)| {
+            let field = format!("phases.{}", name);
+            let (uplink_models, downlink_models) = match value {
+                Value::Table(directions) => {
+                    if let Some(unknown) = directions
+                        .keys()
+                        .find(|key| *key != "uplink" && *key != "downlink")
+                    {
+                        return Err(format!("{}: unknown direction {}", field, unknown));
+                    }
+                    let direction = |direction: &str| match directions.get(direction) {
+                        Some(value) => models(&format!("{}.{}", field, direction), value),
+                        None => Ok(Vec::new()),
+                    };
+                    (direction("uplink")?, direction("downlink")?)
+                }
+                value => {
+                    let models = models(&field, value)?;
+                    (models.clone(), models)
+                }
+            };
+            Ok(Phase {
+                name: name.clone(),
+                uplink_models,
+                downlink_models,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_scenarios() {
+        let scenario = Scenario::parse_toml(
+            r#"
+            queue_num = "0:3"
+            per_connection = true
+            match = ["udp", "dport", "40000:40010"]
+            models = ["random:1,10,20", { type = "bandwidth", rate = 1000, burst = 100, buffer = 1000 }]
+            downlink_models = [{ type = "random", loss = 5 }, { type = "gilbert_elliott", p = 0.5, r = 25, bad_loss = 30 }]
+
+            [phases]
+            good = []
+            congested = { uplink = ["bandwidth:100,10,200"] }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(scenario.settings["queue_num"], vec!["0:3"]);
+        assert_eq!(scenario.settings["per_connection"], vec!["true"]);
+        assert_eq!(
+            scenario.settings["match"],
+            vec!["udp", "dport", "40000:40010"]
+        );
+        let specs = |models: &[QueuingModelConfig]| -> Vec<String> {
+            models.iter().map(|model| model.to_string()).collect()
+        };
+        assert_eq!(
+            specs(&scenario.models),
+            vec!["random:1,10,20", "bandwidth:1000,100,1000"]
+        );
+        assert_eq!(
+            specs(&scenario.downlink_models),
+            vec!["random:5,0,0", "gilbert_elliott:0.5,25,30,0"]
+        );
+        assert_eq!(scenario.phases.len(), 2);
+        assert_eq!(scenario.phases[0].name, "congested");
+        assert_eq!(
+            specs(&scenario.phases[0].uplink_models),
+            vec!["bandwidth:100,10,200"]
+        );
+        assert!(scenario.phases[0].downlink_models.is_empty());
+
+        let scenario = Scenario::parse_yaml(
+            "log_level: debug\nmodels:\n  - type: bandwidth\n    rate: 10\n    burst: 1\n    buffer: 10\n",
+        )
+        .unwrap();
+        assert_eq!(scenario.settings["log_level"], vec!["debug"]);
+
+        assert_eq!(specs(&scenario.models), vec!["bandwidth:10,1,10"]);
+
+        let error = |toml: &str| Scenario::parse_toml(toml).err().unwrap();
+        assert_eq!(
+            error("models = [{ type = \"bandwidth\", rate = 10 }]"),
+            "models[0]: bandwidth needs burst"
+        );
+        assert_eq!(
+            error("models = [{ type = \"random\", loss = -1 }]"),
+            "models[0]: loss of random must be a positive integer"
+        );
+        assert_eq!(
+            error("models = [\"random:1,x,2\"]"),
+            "models[0]: invalid value x of random: invalid digit found in string"
+        );
+        assert_eq!(
+            error("uplink_models = [{ type = \"random\", los = 1 }]"),
+            "uplink_models[0]: unknown parameter los of random"
+        );
+        assert_eq!(
+            error("queue_num = { first = 1 }"),
+            "queue_num must be a value"
+        );
+        assert_eq!(error("mtu = [1500, 9000]"), "mtu must be a value");
+    }
+}
@@ -0,0 +1,228 @@
+use crate::nfqueue_wrapper::NfqPacket;
+use crate::queuing_model::QueuingModel;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::hash::Hash;
+use std::time::Duration;
+
+/// Keeps the queuing models of all connections together with a global timer
+/// (min-heap) of their next deadlines, so only models with due packets get dequeued.
+pub struct Scheduler<K, M> {
+    models: Vec<M>,
+    index: HashMap<K, usize>,
+    // deadline each model is currently registered with in the timer heap,
+    // heap entries not matching it are stale and skipped
+    scheduled: Vec<Option<Duration>>,
+    deadlines: BinaryHeap<Reverse<(Duration, usize)>>,
+}
+
+impl<K: Hash + Eq, M: QueuingModel> Scheduler<K, M> {
+    pub fn new() -> Self {
+        Scheduler {
+            models: Vec::new(),
+            index: HashMap::new(),
+            scheduled: Vec::new(),
+            deadlines: BinaryHeap::new(),
+        }
+    }
+
+    /// Enqueues a packet into the model of the given key, the model is created on first use.
+    pub fn enqueue<F>(&mut self, key: K, packet: NfqPacket, time_now: Duration, create: F)
+    where
+        F: FnOnce(&K) -> M,
+    {
+        let models = &mut self.models;
+        let scheduled = &mut self.scheduled;
+        let index = *self.index.entry(key).or_insert_with_key(|key| {
+            models.push(create(key));
+            scheduled.push(None);
+            models.len() - 1
+        });
+        self.models[index].enqueue(packet, time_now);
+        self.reschedule(index);
+    }
+
+    /// Removes the packet which arrived first, of all connections
+    pub fn evict_oldest(&mut self) -> Option<NfqPacket> {
+        let (_, index) = self
+            .models
+            .iter()
+            .enumerate()
+            .filter_map(|(index, model)| Some((model.oldest_arrival()?, index)))
+            .min()?;
+        let packet = self.models[index].evict();
+        self.reschedule(index);
+        packet
+    }
+
+    /// Removes all queued packets
+    pub fn flush(&mut self) -> Vec<NfqPacket> {
+        let packets = self
+            .models
+            .iter_mut()
+            .flat_map(|model| model.flush())
+            .collect();
+        self.scheduled
+            .iter_mut()
+            .for_each(|scheduled| *scheduled = None);
+        self.deadlines.clear();
+        packets
+    }
+
+    /// Id, key and model of all connections, ordered by id
+    pub fn connections(&self) -> Vec<(usize, &K, &M)> {
+        let mut connections: Vec<_> = self
+            .index
+            .iter()
+            .map(|(key, index)| (*index, key, &self.models[*index]))
+            .collect();
+        connections.sort_by_key(|(index, _, _)| *index);
+        connections
+    }
+
+    /// Calls `f` with id, key and model of all connections, models may be changed by it
+    pub fn update<F>(&mut self, mut f: F)
+    where
+        F: FnMut(usize, &K, &mut M),
+    {
+        for (key, index) in self.index.iter() {
+            f(*index, key, &mut self.models[*index]);
+        }
+        for index in 0..self.models.len() {
+            self.reschedule(index);
+        }
+    }
+
+    /// Earliest deadline of all models
+    pub fn next_deadline(&mut self) -> Option<Duration> {
+        while let Some(Reverse((deadline, index))) = self.deadlines.peek().copied() {
+            if self.scheduled[index] == Some(deadline) {
+                return Some(deadline);
+            }
+            self.deadlines.pop();
+        }
+        None
+    }
+
+    /// Dequeues all models whose deadline has been reached.
+    pub fn release(&mut self, time_now: Duration) -> Vec<NfqPacket> {
+        let mut due = Vec::new();
+        while let Some(Reverse((deadline, index))) = self.deadlines.peek().copied() {
+            if deadline > time_now {
+                break;
+            }
+            self.deadlines.pop();
+            if self.scheduled[index] == Some(deadline) {
+                self.scheduled[index] = None;
+                due.push(index);
+            }
+        }
+
+        let mut packets = Vec::new();
+        for index in due {
+            packets.append(&mut self.models[index].dequeue(time_now));
+            self.reschedule(index);
+        }
+        packets
+    }
+
+    fn reschedule(&mut self, index: usize) {
+        let deadline = match self.models[index].next_deadline() {
+            Some(deadline) => deadline,
+            None => return,
+        };
+        // a later deadline than the registered one is picked up when the
+        // registered one fires
+        if self.scheduled[index].is_none_or(|scheduled| deadline < scheduled) {
+            self.scheduled[index] = Some(deadline);
+            self.deadlines.push(Reverse((deadline, index)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queuing_model::random_queuing_model::RandomQueuingModel;
+    use crate::queuing_model::ModelStats;
+    use std::fmt::Display;
+
+    // has deadlines at fixed times and counts how often it gets dequeued
+    struct TimerModel {
+        times: Vec<Duration>,
+        dequeued: usize,
+    }
+
+    impl QueuingModel for TimerModel {
+        fn enqueue(&mut self, _: NfqPacket, _: Duration) {}
+
+        fn dequeue(&mut self, time_now: Duration) -> Vec<NfqPacket> {
+            self.times.retain(|time| *time > time_now);
+            self.dequeued += 1;
+            Vec::new()
+        }
+
+        fn next_deadline(&self) -> Option<Duration> {
+            self.times.iter().min().copied()
+        }
+
+        fn oldest_arrival(&self) -> Option<std::time::Instant> {
+            None
+        }
+
+        fn evict(&mut self) -> Option<NfqPacket> {
+            None
+        }
+
+        fn stats(&self) -> ModelStats {
+            ModelStats::default()
+        }
+    }
+
+    impl Display for TimerModel {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "timer model")
+        }
+    }
+
+    #[test]
+    fn dequeue_only_due_models() {
+        let mut scheduler: Scheduler<u32, TimerModel> = Scheduler::new();
+        for (key, times) in [(1, vec![30, 10]), (2, vec![20]), (3, vec![])] {
+            let times = times.into_iter().map(Duration::from_micros).collect();
+            scheduler.models.push(TimerModel { times, dequeued: 0 });
+            scheduler.scheduled.push(None);
+            scheduler.index.insert(key, scheduler.models.len() - 1);
+            scheduler.reschedule(scheduler.models.len() - 1);
+        }
+
+        assert_eq!(scheduler.next_deadline(), Some(Duration::from_micros(10)));
+        scheduler.release(Duration::from_micros(15));
+        assert_eq!(scheduler.next_deadline(), Some(Duration::from_micros(20)));
+        scheduler.release(Duration::from_micros(30));
+        assert_eq!(scheduler.next_deadline(), None);
+
+        let dequeued: Vec<_> = scheduler.models.iter().map(|m| m.dequeued).collect();
+        assert_eq!(dequeued, vec![2, 1, 0]);
+    }
+
+    #[test]
+    fn evict_packet_which_arrived_first_of_all_connections() {
+        let mut scheduler: Scheduler<u32, RandomQueuingModel> = Scheduler::new();
+        let start = std::time::Instant::now();
+        let delay = Duration::from_secs(1);
+        // the connection of the oldest packet changes with each eviction
+        for (id, key, arrival) in [(1, 1, 20), (2, 2, 10), (3, 1, 30), (4, 2, 40)] {
+            let mut packet = NfqPacket::detached(id, 1);
+            packet.arrival = start + Duration::from_millis(arrival);
+            scheduler.enqueue(key, packet, Duration::ZERO, |_| {
+                RandomQueuingModel::new(0).with_delay_range((delay, delay))
+            });
+        }
+
+        let evicted: Vec<u32> = std::iter::from_fn(|| scheduler.evict_oldest())
+            .map(|packet| packet.id)
+            .collect();
+        assert_eq!(evicted, vec![2, 1, 3, 4]);
+    }
+}
@@ -0,0 +1,38 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// What happens to queued packets on shutdown
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShutdownMode {
+    /// accept all queued packets immediately
+    Flush,
+    /// release queued packets at their scheduled time
+    Drain,
+}
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+static FORCED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_signal(_: libc::c_int) {
+    // a second signal cuts draining short, teardown still runs
+    if REQUESTED.swap(true, Ordering::SeqCst) {
+        FORCED.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Requests a graceful shutdown on SIGINT and SIGTERM
+pub fn install_handler() {
+    let handler = handle_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/// A second signal was received, queued packets are accepted immediately
+pub fn forced() -> bool {
+    FORCED.load(Ordering::SeqCst)
+}
@@ -0,0 +1,298 @@
+use crate::protocol::{Direction, ProtocolInfo};
+use std::collections::HashMap;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+
+// sockets get closed and their ports reused, so the owner of a local socket is
+// looked up again after a while
+const OWNER_CACHE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct LocalSocket {
+    protocol: u8,
+    ip: [u8; 4],
+    port: u16,
+}
+
+#[derive(Clone, Default)]
+struct SocketOwner {
+    uid: Option<u32>,
+    processes: Vec<String>,
+}
+
+// owner per local socket and when it was looked up, none while the lookup is pending
+type OwnerCache = HashMap<LocalSocket, (Instant, Option<SocketOwner>)>;
+
+/// Restricts the degradation to the traffic of a user and/or a process.
+///
+/// The owner of a packet is taken from the uid the kernel delivers with it, if
+/// available. Otherwise (and for the process name) the local socket is looked up
+/// in /proc/net/{tcp,udp} and its inode in the fd tables of /proc/<pid>, once per
+/// local socket and cache timeout. The lookups run on a resolver thread, so they
+/// don't hold up receiving, packets of a socket whose owner is not known yet are
+/// not targeted. Expired owners are removed on each lookup.
+pub struct TargetFilter {
+    user: Option<u32>,
+    process: Option<String>,
+    owners: Arc<Mutex<OwnerCache>>,
+    resolver: mpsc::Sender<LocalSocket>,
+}
+
+impl TargetFilter {
+    pub fn new(user: Option<u32>, process: Option<String>) -> Option<Self> {
+        if user.is_none() && process.is_none() {
+            return None;
+        }
+        let owners = Arc::new(Mutex::new(HashMap::new()));
+        let (resolver, requests) = mpsc::channel();
+        let resolver_owners = Arc::clone(&owners);
+        let with_processes = process.is_some();
+        // ends when the filter is dropped
+        std::thread::Builder::new()
+            .name("owner_resolver".to_string())
+            .spawn(move || {
+                for socket in requests {
+                    resolve(&resolver_owners, socket, with_processes);
+                }
+            })
+            .expect("failed to spawn owner resolver thread");
+        Some(TargetFilter {
+            user,
+            process,
+            owners,
+            resolver,
+        })
+    }
+
+    pub fn matches(&self, info: &ProtocolInfo, direction: Direction, uid: Option<u32>) -> bool {
+        if self.process.is_none() {
+            if let (Some(user), Some(uid)) = (self.user, uid) {
+                return user == uid;
+            }
+        }
+
+        let socket = match direction {
+            Direction::Uplink => LocalSocket {
+                protocol: info.protocol,
+                ip: info.source_ip,
+                port: info.source_port,
+            },
+            Direction::Downlink => LocalSocket {
+                protocol: info.protocol,
+                ip: info.destination_ip,
+                port: info.destination_port,
+            },
+        };
+        let owner = match self.owner(socket) {
+            Some(owner) => owner,
+            None => return false,
+        };
+
+        let user_matches = match self.user {
+            Some(user) => uid.or(owner.uid) == Some(user),
+            None => true,
+        };
+        let process_matches = match &self.process {
+            Some(process) => owner.processes.iter().any(|name| name == process),
+            None => true,
+        };
+        user_matches && process_matches
+    }
+
+    // cached owner, looked up on the resolver thread if unknown or expired
+    fn owner(&self, socket: LocalSocket) -> Option<SocketOwner> {
+        let mut owners = self.owners.lock().unwrap();
+        match owners.get_mut(&socket) {
+            Some((looked_up, owner)) => {
+                if looked_up.elapsed() >= OWNER_CACHE_TIMEOUT {
+                    // the old owner is used until the new one is known
+                    *looked_up = Instant::now();
+                    let _ = self.resolver.send(socket);
+                }
+                owner.clone()
+            }
+            None => {
+                owners.insert(socket, (Instant::now(), None));
+                let _ = self.resolver.send(socket);
+                None
+            }
+        }
+    }
+}
+
+fn resolve(owners: &Mutex<OwnerCache>, socket: LocalSocket, with_processes: bool) {
+    let owner = lookup_owner(socket, with_processes).unwrap_or_default();
+    log::debug!(
+        "owner of local port {}: uid {:?}, processes {:?}",
+        socket.port,
+        owner.uid,
+        owner.processes
+    );
+    let mut owners = owners.lock().unwrap();
+    owners.retain(|_, (looked_up, _)| looked_up.elapsed() < OWNER_CACHE_TIMEOUT);
+    owners.insert(socket, (Instant::now(), Some(owner)));
+}
+
+fn lookup_owner(socket: LocalSocket, with_processes: bool) -> Option<SocketOwner> {
+    let table = match socket.protocol {
+        IPPROTO_TCP => "/proc/net/tcp",
+        IPPROTO_UDP => "/proc/net/udp",
+        _ => return None,
+    };
+    let content = std::fs::read_to_string(table).ok()?;
+    let (uid, inode) = content
+        .lines()
+        .skip(1)
+        .filter_map(parse_socket_line)
+        .filter(|(ip, port, _, _)| {
+            *port == socket.port && (*ip == socket.ip || *ip == [0, 0, 0, 0])
+        })
+        // a socket bound to the exact address wins over a wildcard bound one
+        .max_by_key(|(ip, _, _, _)| *ip == socket.ip)
+        .map(|(_, _, uid, inode)| (uid, inode))?;
+
+    let processes = if with_processes {
+        processes_with_socket(inode)
+    } else {
+        Vec::new()
+    };
+    Some(SocketOwner {
+        uid: Some(uid),
+        processes,
+    })
+}
+
+// parses "sl local_address rem_address st tx_queue:rx_queue tr:tm->when retrnsmt uid timeout inode ..."
+// into (local ip, local port, uid, inode)
+fn parse_socket_line(line: &str) -> Option<([u8; 4], u16, u32, u64)> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() < 10 {
+        return None;
+    }
+    let (ip, port) = fields[1].split_once(':')?;
+    // the address is printed as the native endian value of the network order bytes
+    let ip = u32::from_str_radix(ip, 16).ok()?.to_ne_bytes();
+    let port = u16::from_str_radix(port, 16).ok()?;
+    let uid = fields[7].parse().ok()?;
+    let inode = fields[9].parse().ok()?;
+    Some((ip, port, uid, inode))
+}
+
+fn processes_with_socket(inode: u64) -> Vec<String> {
+    let link = format!("socket:[{}]", inode);
+    let mut processes = Vec::new();
+    let proc_dir = match std::fs::read_dir("/proc") {
+        Ok(dir) => dir,
+        Err(_) => return processes,
+    };
+    for entry in proc_dir.flatten() {
+        let pid_dir = entry.path();
+        let fds = match std::fs::read_dir(pid_dir.join("fd")) {
+            Ok(fds) => fds,
+            Err(_) => continue,
+        };
+        let has_socket = fds
+            .flatten()
+            .filter_map(|fd| std::fs::read_link(fd.path()).ok())
+            .any(|target| target.as_os_str() == link.as_str());
+        if !has_socket {
+            continue;
+        }
+        // comm is truncated to 15 characters, the executable name is not
+        if let Ok(comm) = std::fs::read_to_string(pid_dir.join("comm")) {
+            processes.push(comm.trim_end().to_string());
+        }
+        if let Some(exe) = std::fs::read_link(pid_dir.join("exe"))
+            .ok()
+            .and_then(|exe| {
+                exe.file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+            })
+        {
+            processes.push(exe);
+        }
+    }
+    processes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_proc_net_socket_line() {
+        let line = "   12: 0100007F:9C40 00000000:0000 07 00000000:00000000 00:00000000 00000000  1000        0 34567 2 0000000000000000 0";
+        let (ip, port, uid, inode) = parse_socket_line(line).unwrap();
+        assert_eq!(
+            ip,
+            u32::from_str_radix("0100007F", 16).unwrap().to_ne_bytes()
+        );
+        assert_eq!(port, 40000);
+        assert_eq!(uid, 1000);
+        assert_eq!(inode, 34567);
+        assert!(parse_socket_line("  sl  local_address rem_address").is_none());
+    }
+
+    #[test]
+    fn expired_owners_are_removed() {
+        let filter = TargetFilter::new(Some(1000), None).unwrap();
+        let socket = |port| LocalSocket {
+            protocol: 0,
+            ip: [127, 0, 0, 1],
+            port,
+        };
+        let expired = Instant::now().checked_sub(OWNER_CACHE_TIMEOUT * 2).unwrap();
+        for port in 1..=100 {
+            filter
+                .owners
+                .lock()
+                .unwrap()
+                .insert(socket(port), (expired, Some(SocketOwner::default())));
+        }
+        resolve(&filter.owners, socket(1), false);
+        resolve(&filter.owners, socket(2), false);
+        let owners = filter.owners.lock().unwrap();
+        assert_eq!(owners.len(), 2);
+        assert!(owners.contains_key(&socket(1)));
+    }
+
+    #[test]
+    fn unresolved_sockets_are_not_targeted() {
+        let filter = TargetFilter::new(None, Some("app".to_string())).unwrap();
+        let info = ProtocolInfo {
+            source_ip: [127, 0, 0, 1],
+            source_port: 40000,
+            protocol: 0,
+            ..ProtocolInfo::default()
+        };
+        let socket = LocalSocket {
+            protocol: 0,
+            ip: [127, 0, 0, 1],
+            port: 40000,
+        };
+        assert!(!filter.matches(&info, Direction::Uplink, None));
+
+        // wait for the resolver, the owner of an unknown protocol is unknown as well
+        let resolved = || filter.owners.lock().unwrap()[&socket].1.is_some();
+        let started = Instant::now();
+        while !resolved() && started.elapsed() < Duration::from_secs(5) {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(resolved());
+        assert!(!filter.matches(&info, Direction::Uplink, None));
+
+        let owner = SocketOwner {
+            uid: None,
+            processes: vec!["app".to_string()],
+        };
+        filter
+            .owners
+            .lock()
+            .unwrap()
+            .insert(socket, (Instant::now(), Some(owner)));
+        assert!(filter.matches(&info, Direction::Uplink, None));
+    }
+}
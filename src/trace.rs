@@ -0,0 +1,293 @@
+use crate::nfqueue_wrapper::{NfqPacket, Verdict};
+use crate::protocol::ProtocolInfo;
+use crate::queuing_model::{Action, Decision};
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::{mpsc, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceFormat {
+    Csv,
+    JsonLines,
+}
+
+enum Trace {
+    Csv(Box<csv::Writer<File>>),
+    JsonLines(BufWriter<File>),
+}
+
+enum Message {
+    Record(Record),
+    /// flush and stop, acknowledged on the sender
+    Finish(mpsc::Sender<()>),
+}
+
+// records are formatted and written on the trace thread, so packets are never held
+// up by file i/o
+static TRACE: OnceLock<mpsc::Sender<Message>> = OnceLock::new();
+
+const CSV_HEADER: [&str; 9] = [
+    "arrival",
+    "release",
+    "verdict",
+    "protocol",
+    "source",
+    "destination",
+    "size",
+    "delay_ms",
+    "decisions",
+];
+
+/// Starts writing one record per packet with a verdict: flow, size, arrival and
+/// release time and the decisions of the models in chain order
+pub fn start(path: &str, format: TraceFormat) -> Result<(), String> {
+    let file = File::create(path).map_err(|e| format!("failed to create {}: {}", path, e))?;
+    let trace = match format {
+        TraceFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(file);
+            writer
+                .write_record(CSV_HEADER)
+                .map_err(|e| format!("failed to write {}: {}", path, e))?;
+            Trace::Csv(Box::new(writer))
+        }
+        TraceFormat::JsonLines => Trace::JsonLines(BufWriter::new(file)),
+    };
+    let (sender, messages) = mpsc::channel();
+    if TRACE.set(sender).is_err() {
+        return Err("trace already started".to_string());
+    }
+    std::thread::Builder::new()
+        .name("trace".to_string())
+        .spawn(move || write(trace, messages))
+        .map_err(|e| format!("failed to spawn trace thread: {}", e))?;
+    log::info!("tracing packets to {}", path);
+    Ok(())
+}
+
+/// Writes the record of the packet if a trace was started
+pub fn record(packet: &NfqPacket, verdict: Verdict) {
+    let trace = match TRACE.get() {
+        Some(trace) => trace,
+        None => return,
+    };
+    let release = SystemTime::now();
+    let delay = packet.arrival.elapsed();
+    let arrival = release.checked_sub(delay).unwrap_or(release);
+    let record = Record {
+        arrival: since_epoch(arrival),
+        release: since_epoch(release),
+        verdict,
+        flow: ProtocolInfo::from_ipv4_header(packet.get_payload()),
+        size: packet.wire_len,
+        delay,
+        decisions: packet.decisions.clone(),
+    };
+    // the trace thread has stopped after finish
+    let _ = trace.send(Message::Record(record));
+}
+
+/// Writes the buffered records to the trace file
+pub fn finish() {
+    if let Some(trace) = TRACE.get() {
+        let (done, finished) = mpsc::channel();
+        if trace.send(Message::Finish(done)).is_ok() {
+            let _ = finished.recv();
+        }
+    }
+}
+
+fn write(mut trace: Trace, messages: mpsc::Receiver<Message>) {
+    for message in messages {
+        match message {
+            Message::Record(record) => {
+                let result = match &mut trace {
+                    Trace::Csv(writer) => {
+                        writer.write_record(record.csv()).map_err(|e| e.to_string())
+                    }
+                    Trace::JsonLines(writer) => {
+                        writeln!(writer, "{}", record.json()).map_err(|e| e.to_string())
+                    }
+                };
+                if let Err(e) = result {
+                    log::error!("failed to trace packet: {}", e);
+                }
+            }
+            Message::Finish(done) => {
+                let result = match &mut trace {
+                    Trace::Csv(writer) => writer.flush(),
+                    Trace::JsonLines(writer) => writer.flush(),
+                };
+                if let Err(e) = result {
+                    log::error!("failed to write trace: {}", e);
+                }
+                let _ = done.send(());
+                return;
+            }
+        }
+    }
+}
+
+struct Record {
+    // since the unix epoch
+    arrival: Duration,
+    release: Duration,
+    verdict: Verdict,
+    flow: ProtocolInfo,
+    size: usize,
+    delay: Duration,
+    decisions: Vec<Decision>,
+}
+
+impl Record {
+    fn verdict(&self) -> &'static str {
+        match self.verdict {
+            Verdict::Accept => "accept",
+            Verdict::Drop => "drop",
+        }
+    }
+
+    fn source(&self) -> String {
+        format!(
+            "{}:{}",
+            self.flow.source_ip_to_string(),
+            self.flow.source_port
+        )
+    }
+
+    fn destination(&self) -> String {
+        format!(
+            "{}:{}",
+            self.flow.destination_ip_to_string(),
+            self.flow.destination_port
+        )
+    }
+
+    // fields in the order of the header, decisions as <model>:<action>[:<delay in ms>],
+    // separated by ;
+    fn csv(&self) -> [String; 9] {
+        let decisions: Vec<String> = self
+            .decisions
+            .iter()
+            .map(|decision| match decision.action {
+                Action::Delay(delay) => format!("{}:delay:{:.3}", decision.model, millis(delay)),
+                Action::Queue => format!("{}:queue", decision.model),
+                Action::Drop => format!("{}:drop", decision.model),
+            })
+            .collect();
+        [
+            format!("{:.6}", self.arrival.as_secs_f64()),
+            format!("{:.6}", self.release.as_secs_f64()),
+            self.verdict().to_string(),
+            self.flow.protocol.to_string(),
+            self.source(),
+            self.destination(),
+            self.size.to_string(),
+            format!("{:.3}", millis(self.delay)),
+            decisions.join(";"),
+        ]
+    }
+
+    fn json(&self) -> String {
+        let record = JsonRecord {
+            arrival: self.arrival.as_secs_f64(),
+            release: self.release.as_secs_f64(),
+            verdict: self.verdict(),
+            protocol: self.flow.protocol,
+            source: self.source(),
+            destination: self.destination(),
+            size: self.size,
+            delay_ms: millis(self.delay),
+            decisions: self
+                .decisions
+                .iter()
+                .map(|decision| {
+                    let (action, delay_ms) = match decision.action {
+                        Action::Delay(delay) => ("delay", Some(millis(delay))),
+                        Action::Queue => ("queue", None),
+                        Action::Drop => ("drop", None),
+                    };
+                    JsonDecision {
+                        model: decision.model,
+                        action,
+                        delay_ms,
+                    }
+                })
+                .collect(),
+        };
+        serde_json::to_string(&record).expect("trace records serialize")
+    }
+}
+
+#[derive(Serialize)]
+struct JsonRecord {
+    arrival: f64,
+    release: f64,
+    verdict: &'static str,
+    protocol: u8,
+    source: String,
+    destination: String,
+    size: usize,
+    delay_ms: f64,
+    decisions: Vec<JsonDecision>,
+}
+
+#[derive(Serialize)]
+struct JsonDecision {
+    model: &'static str,
+    action: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    delay_ms: Option<f64>,
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+fn since_epoch(time: SystemTime) -> Duration {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_records() {
+        let decisions = [
+            Decision::new("random", Action::Delay(Duration::from_millis(20))),
+            Decision::new("bandwidth", Action::Drop),
+        ];
+        let record = Record {
+            arrival: Duration::from_secs(1),
+            release: Duration::from_millis(1020),
+            verdict: Verdict::Drop,
+            flow: ProtocolInfo {
+                source_ip: [10, 0, 0, 1],
+                source_port: 5000,
+                destination_ip: [10, 0, 0, 2],
+                destination_port: 6000,
+                protocol: 17,
+            },
+            size: 100,
+            delay: Duration::from_millis(20),
+            decisions: decisions.to_vec(),
+        };
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        writer.write_record(CSV_HEADER).unwrap();
+        writer.write_record(record.csv()).unwrap();
+        assert_eq!(
+            String::from_utf8(writer.into_inner().unwrap()).unwrap(),
+            "arrival,release,verdict,protocol,source,destination,size,delay_ms,decisions\n\
+             1.000000,1.020000,drop,17,10.0.0.1:5000,10.0.0.2:6000,100,20.000,random:delay:20.000;bandwidth:drop\n"
+        );
+        assert_eq!(
+            record.json(),
+            "{\"arrival\":1.0,\"release\":1.02,\"verdict\":\"drop\",\"protocol\":17,\
+             \"source\":\"10.0.0.1:5000\",\"destination\":\"10.0.0.2:6000\",\"size\":100,\
+             \"delay_ms\":20.0,\"decisions\":[{\"model\":\"random\",\"action\":\"delay\",\
+             \"delay_ms\":20.0},{\"model\":\"bandwidth\",\"action\":\"drop\"}]}"
+        );
+    }
+}
@@ -64,10 +64,12 @@ mod tests {
 
     #[test]
     fn serialize_and_deserialize_packet() {
-        let mut p = Packet::default();
-        p.client_send_time = Duration::from_nanos(1234567000);
-        p.client_receive_time = Duration::from_nanos(9871234000);
-        p.server_send_time = Duration::from_nanos(876445000);
+        let p = Packet {
+            client_send_time: Duration::from_nanos(1234567000),
+            client_receive_time: Duration::from_nanos(9871234000),
+            server_send_time: Duration::from_nanos(876445000),
+            ..Default::default()
+        };
         let serialized = p.as_bytes();
         let p2 = Packet::from_bytes(serialized.as_slice()).unwrap();
         assert_eq!(p2.client_send_time, p.client_send_time);
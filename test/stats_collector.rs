@@ -18,7 +18,7 @@ fn thread_func(rx: mpsc::Receiver<SocketPacket>) {
         if let Ok(packet) = received {
             let file_name = packet.socket_num.to_string() + "_stats.txt";
 
-            if files.get(&packet.socket_num).is_none() {
+            if !files.contains_key(&packet.socket_num) {
                 match std::fs::remove_file(&file_name) {
                     Err(e) if e.kind() == ErrorKind::NotFound => (),
                     Err(e) => log::error!("Error removing file {}: {}", file_name, e),
@@ -50,7 +50,7 @@ impl UdpClient {
         let mut send_sockets: Vec<UdpSocket> = Vec::new();
         let mut receive_sockets: Vec<UdpSocket> = Vec::new();
         let mut current_server_port = self.cfg.server_port_range.0;
-        for (_, port) in (self.cfg.client_port_range.0..self.cfg.client_port_range.1).enumerate() {
+        for port in self.cfg.client_port_range.0..self.cfg.client_port_range.1 {
             let client_socket =
                 std::net::UdpSocket::bind(self.cfg.client_ip.to_owned() + ":" + &port.to_string())
                     .unwrap();
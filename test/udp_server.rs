@@ -14,7 +14,7 @@ impl UdpServer {
 
     pub fn start(&mut self) {
         let mut sockets: Vec<UdpSocket> = Vec::new();
-        for (_, port) in (self.cfg.server_port_range.0..self.cfg.server_port_range.1).enumerate() {
+        for port in self.cfg.server_port_range.0..self.cfg.server_port_range.1 {
             let server_socket =
                 std::net::UdpSocket::bind(self.cfg.server_ip.to_owned() + ":" + &port.to_string())
                     .unwrap();